@@ -0,0 +1,258 @@
+use std::{fs::File, path::{Path, PathBuf}, sync::{atomic::{AtomicBool, Ordering}, mpsc::{self, Receiver}, Arc}, thread};
+use ebur128::{EbuR128, Mode};
+use rodio::{Decoder, Source};
+use serde::{Deserialize, Serialize};
+use crate::{library::SongId, replaygain::ReplayGain};
+
+/// Loudness ReplayGain 2.0 normalises to, gains are the distance from a song's loudness to this.
+const REFERENCE_LUFS: f64 = -18.0;
+
+/// Frames handed to the meter at a time.
+const CHUNK_FRAMES: usize = 4096;
+
+/// Result of measuring a song according to EBU R128.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Loudness {
+    pub integrated: f64,            //LUFS
+    pub true_peak: f64,             //linear, can go above 1.0 between samples
+    pub gated_blocks: u64,          //gating blocks that passed the gates, with their summed energy the album
+    pub gated_energy: f64,          //loudness can be worked out from songs that were measured separately
+    pub album_integrated: Option<f64>,  //filled in once every song of the album is measured
+    pub album_peak: Option<f64>,
+}
+
+impl Loudness {
+    /// The measurement as ReplayGain values, for songs that don't have them in their tags. A loudness that
+    /// couldn't be measured, like that of a silent song, gives no gain.
+    pub fn replay_gain(&self) -> ReplayGain {
+        ReplayGain {
+            track_gain: Some((REFERENCE_LUFS - self.integrated) as f32).filter(|g| g.is_finite()),
+            track_peak: Some(self.true_peak as f32),
+            album_gain: self.album_integrated.map(|l| (REFERENCE_LUFS - l) as f32).filter(|g| g.is_finite()),
+            album_peak: self.album_peak.map(|p| p as f32),
+        }
+    }
+}
+
+/// Loudness and peak of an album from the measurements of its songs. The gated energy of every song
+/// is pooled, which is close to measuring the album as one long track without needing it decoded in one go.
+/// The loudness is None when no block of any song got through the gates.
+pub fn album_loudness(songs: &[Loudness]) -> (Option<f64>, f64) {
+    let blocks: u64 = songs.iter().map(|l| l.gated_blocks).sum();
+    let energy: f64 = songs.iter().map(|l| l.gated_energy).sum();
+    let integrated = (blocks > 0)
+        .then(|| 10.0 * (energy / blocks as f64).log10() - 0.691)
+        .filter(|l| l.is_finite());
+    let peak = songs.iter().map(|l| l.true_peak).fold(0.0, f64::max);
+    (integrated, peak)
+}
+
+/// Decodes the whole file and measures it.
+pub fn analyze(path: &Path, cancel: &AtomicBool) -> Result<Loudness, String> {
+    let file = File::open(path).map_err(|e| e.to_string())?;
+    let mut decoder = Decoder::try_from(file).map_err(|e| e.to_string())?;
+    let channels = decoder.channels() as usize;
+    if channels == 0 {
+        return Err("no audio channels".to_string());
+    }
+    let mut meter = EbuR128::new(channels as u32, decoder.sample_rate(), Mode::I | Mode::TRUE_PEAK | Mode::HISTOGRAM)
+        .map_err(|e| e.to_string())?;
+
+    let mut chunk = Vec::with_capacity(CHUNK_FRAMES * channels);
+    loop {
+        chunk.clear();
+        chunk.extend(decoder.by_ref().take(CHUNK_FRAMES * channels));
+        //a cut off final frame would shift every channel after it
+        chunk.truncate(chunk.len() - chunk.len() % channels);
+        if chunk.is_empty() {
+            break;
+        }
+        meter.add_frames_f32(&chunk).map_err(|e| e.to_string())?;
+        if cancel.load(Ordering::Relaxed) {
+            return Err("cancelled".to_string());
+        }
+    }
+
+    let integrated = meter.loudness_global().map_err(|e| e.to_string())?;
+    //silence and songs shorter than one gating block come out as -inf, there's no gain to work out from that
+    if !integrated.is_finite() {
+        return Err("too short or too quiet to measure".to_string());
+    }
+    let mut true_peak: f64 = 0.0;
+    for channel in 0..channels as u32 {
+        true_peak = true_peak.max(meter.true_peak(channel).map_err(|e| e.to_string())?);
+    }
+    let (gated_blocks, gated_energy) = meter.gating_block_count_and_energy().unwrap_or((0, 0.0));
+    Ok(Loudness {
+        integrated,
+        true_peak,
+        gated_blocks,
+        gated_energy,
+        album_integrated: None,
+        album_peak: None,
+    })
+}
+
+pub enum AnalysisMessage {
+    Progress { done: usize, path: PathBuf },
+    Measured(SongId, Loudness),
+    Failed(SongId, String),
+    Finished,
+}
+
+/// Handle to a loudness analysis running on a worker thread, polled by the UI every frame like a `ScanJob`.
+pub struct AnalysisJob {
+    receiver: Receiver<AnalysisMessage>,
+    cancel: Arc<AtomicBool>,
+    pub total: usize,
+    pub done: usize,
+    pub current: PathBuf,
+}
+
+impl AnalysisJob {
+    /// Measures `songs` one after the other. Results come in through `poll` as soon as each song is done,
+    /// so a job that's cancelled or cut short by closing the app only loses the song it was on.
+    pub fn start(songs: Vec<(SongId, PathBuf)>, ctx: egui::Context) -> AnalysisJob {
+        let (sender, receiver) = mpsc::channel();
+        let cancel = Arc::new(AtomicBool::new(false));
+        let worker_cancel = cancel.clone();
+        let total = songs.len();
+
+        thread::spawn(move || {
+            for (i, (id, path)) in songs.into_iter().enumerate() {
+                if worker_cancel.load(Ordering::Relaxed) {
+                    break;
+                }
+                let _ = sender.send(AnalysisMessage::Progress { done: i, path: path.clone() });
+                ctx.request_repaint();
+                let message = match analyze(&path, &worker_cancel) {
+                    Ok(loudness) => AnalysisMessage::Measured(id, loudness),
+                    Err(_) if worker_cancel.load(Ordering::Relaxed) => break,
+                    Err(e) => AnalysisMessage::Failed(id, e),
+                };
+                if sender.send(message).is_err() {
+                    return; //job was dropped, nobody is listening anymore
+                }
+                let _ = sender.send(AnalysisMessage::Progress { done: i + 1, path });
+            }
+            let _ = sender.send(AnalysisMessage::Finished);
+            ctx.request_repaint();
+        });
+
+        AnalysisJob {
+            receiver,
+            cancel,
+            total,
+            done: 0,
+            current: PathBuf::new(),
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.cancel.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.load(Ordering::Relaxed)
+    }
+
+    /// Drains everything the worker sent since the last call, keeping the progress counters up to date.
+    pub fn poll(&mut self) -> Vec<AnalysisMessage> {
+        let mut messages = Vec::new();
+        while let Ok(message) = self.receiver.try_recv() {
+            if let AnalysisMessage::Progress { done, path } = &message {
+                self.done = *done;
+                self.current = path.clone();
+            }
+            messages.push(message);
+        }
+        messages
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn loudness(integrated: f64, gated_blocks: u64, gated_energy: f64) -> Loudness {
+        Loudness {
+            integrated,
+            true_peak: 0.5,
+            gated_blocks,
+            gated_energy,
+            album_integrated: None,
+            album_peak: None,
+        }
+    }
+
+    //16-bit mono PCM
+    fn write_wav(name: &str, samples: &[i16]) -> PathBuf {
+        let rate: u32 = 44100;
+        let data_len = samples.len() as u32 * 2;
+        let mut bytes = Vec::new();
+        bytes.extend(b"RIFF");
+        bytes.extend((36 + data_len).to_le_bytes());
+        bytes.extend(b"WAVEfmt ");
+        bytes.extend(16u32.to_le_bytes());
+        bytes.extend(1u16.to_le_bytes());
+        bytes.extend(1u16.to_le_bytes());
+        bytes.extend(rate.to_le_bytes());
+        bytes.extend((rate * 2).to_le_bytes());
+        bytes.extend(2u16.to_le_bytes());
+        bytes.extend(16u16.to_le_bytes());
+        bytes.extend(b"data");
+        bytes.extend(data_len.to_le_bytes());
+        for sample in samples {
+            bytes.extend(sample.to_le_bytes());
+        }
+        let path = std::env::temp_dir().join(format!("coral_analysis_{}_{}.wav", std::process::id(), name));
+        std::fs::write(&path, bytes).unwrap();
+        path
+    }
+
+    #[test]
+    fn silence_fails_instead_of_measuring_infinity() {
+        let path = write_wav("silence", &vec![0; 44100 * 2]);
+        let result = analyze(&path, &AtomicBool::new(false));
+        let _ = std::fs::remove_file(&path);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn tone_measures_finite_loudness() {
+        let samples: Vec<i16> = (0..44100 * 2)
+            .map(|i| ((i as f32 * 1000.0 * std::f32::consts::TAU / 44100.0).sin() * 8000.0) as i16)
+            .collect();
+        let path = write_wav("tone", &samples);
+        let result = analyze(&path, &AtomicBool::new(false));
+        let _ = std::fs::remove_file(&path);
+        let loudness = result.unwrap();
+        assert!(loudness.integrated.is_finite());
+        assert!(loudness.gated_blocks > 0);
+        assert!(loudness.replay_gain().track_gain.is_some_and(|g| g.is_finite()));
+    }
+
+    #[test]
+    fn album_without_gated_blocks_has_no_loudness() {
+        let (integrated, peak) = album_loudness(&[loudness(-20.0, 0, 0.0), loudness(-20.0, 0, 0.0)]);
+        assert_eq!(integrated, None);
+        assert_eq!(peak, 0.5);
+    }
+
+    #[test]
+    fn album_pools_gated_energy() {
+        //mean block energy of 10^((-20 + 0.691) / 10) is -20 LUFS
+        let energy = 10f64.powf((-20.0 + 0.691) / 10.0);
+        let (integrated, _) = album_loudness(&[loudness(-20.0, 10, energy * 10.0), loudness(-20.0, 30, energy * 30.0)]);
+        assert!((integrated.unwrap() + 20.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn unmeasurable_loudness_gives_no_gain() {
+        let mut song = loudness(f64::NEG_INFINITY, 0, 0.0);
+        song.album_integrated = Some(f64::NEG_INFINITY);
+        let gain = song.replay_gain();
+        assert_eq!(gain.track_gain, None);
+        assert_eq!(gain.album_gain, None);
+    }
+}
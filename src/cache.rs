@@ -0,0 +1,92 @@
+use std::{collections::HashMap, fs, path::{Path, PathBuf}, sync::{atomic::{AtomicU64, Ordering}, Mutex}, thread, time::SystemTime};
+use serde::{Deserialize, Serialize};
+use crate::library::SongInfo;
+
+/// Bump whenever `SongInfo` or the layout below changes, old caches are then thrown away and rebuilt.
+const CACHE_VERSION: u32 = 7;
+
+#[derive(Deserialize)]
+struct CacheHeader {
+    version: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct LibraryCache {
+    version: u32,
+    songs: Vec<SongInfo>,
+}
+
+/// Numbers the snapshots in the order they were taken, so an older one finishing late can't overwrite a newer one.
+static NEXT_GENERATION: AtomicU64 = AtomicU64::new(1);
+/// Held while a snapshot is written, holds the generation last written.
+static WRITTEN: Mutex<u64> = Mutex::new(0);
+
+pub fn cache_path() -> Option<PathBuf> {
+    eframe::storage_dir("Coral").map(|dir| dir.join("library_cache.ron"))
+}
+
+/// Loads the cached songs keyed by path. A missing, unreadable or outdated cache gives an empty map.
+pub fn load(path: &Path) -> HashMap<PathBuf, SongInfo> {
+    let Ok(contents) = fs::read_to_string(path) else {
+        return HashMap::new();
+    };
+    match ron::from_str::<CacheHeader>(&contents) {
+        Ok(header) if header.version == CACHE_VERSION => {}
+        _ => {
+            println!("Library cache is outdated, rebuilding");
+            return HashMap::new();
+        }
+    }
+    match ron::from_str::<LibraryCache>(&contents) {
+        Ok(cache) => cache.songs.into_iter().map(|s| (s.path.clone(), s)).collect(),
+        Err(e) => {
+            println!("Failed to read library cache: {e}");
+            HashMap::new()
+        }
+    }
+}
+
+/// Writes the songs on a worker thread. Saves are written one at a time, a snapshot older than the last one
+/// written is dropped.
+pub fn save_in_background(path: PathBuf, songs: Vec<SongInfo>) {
+    let generation = NEXT_GENERATION.fetch_add(1, Ordering::Relaxed);
+    thread::spawn(move || write_snapshot(&path, songs, generation));
+}
+
+/// Writes the songs right away, waiting for a save that's still running in the background.
+pub fn save(path: &Path, songs: Vec<SongInfo>) {
+    let generation = NEXT_GENERATION.fetch_add(1, Ordering::Relaxed);
+    write_snapshot(path, songs, generation);
+}
+
+fn write_snapshot(path: &Path, songs: Vec<SongInfo>, generation: u64) {
+    let mut written = WRITTEN.lock().unwrap_or_else(|e| e.into_inner());
+    if *written > generation {
+        return;
+    }
+    let cache = LibraryCache {
+        version: CACHE_VERSION,
+        songs,
+    };
+    let result = ron::to_string(&cache)
+        .map_err(|e| e.to_string())
+        .and_then(|contents| {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+            }
+            //write next to the real file first so a crash mid-write can't leave a truncated cache behind
+            let tmp = path.with_extension("ron.tmp");
+            fs::write(&tmp, contents).map_err(|e| e.to_string())?;
+            fs::rename(&tmp, path).map_err(|e| e.to_string())
+        });
+    match result {
+        Ok(()) => *written = generation,
+        Err(e) => println!("Failed to write library cache: {e}"),
+    }
+}
+
+/// Size and modification time of `path`, used to tell whether a cached entry is still current.
+pub fn file_stamp(path: &Path) -> Option<(u64, SystemTime)> {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.len(), metadata.modified().ok()?))
+}
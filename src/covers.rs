@@ -0,0 +1,179 @@
+use std::{collections::HashMap, fs, path::{Path, PathBuf}, sync::mpsc::{self, Receiver, Sender}, thread};
+use egui::{ColorImage, TextureHandle, TextureOptions};
+use lofty::{config::ParseOptions, file::TaggedFileExt, picture::PictureType, probe::Probe};
+use crate::library::AlbumKey;
+
+/// Covers are scaled down to fit this many pixels on their longer side before they're uploaded.
+const COVER_SIZE: u32 = 128;
+
+/// Upper bound for the pixel data of all uploaded covers, the least recently drawn ones are freed past it.
+const MAX_TEXTURE_BYTES: usize = 32 * 1024 * 1024;
+
+/// File names checked next to the songs when none of them has an embedded picture, in order of preference.
+const FOLDER_IMAGES: [&str; 4] = ["cover", "folder", "front", "album"];
+const FOLDER_IMAGE_EXTENSIONS: [&str; 3] = ["jpg", "jpeg", "png"];
+
+enum CoverEntry {
+    Pending,    //handed to the worker, nothing to draw yet
+    Missing,    //no embedded picture and no image in the folder, or it failed to decode
+    Loaded { texture: TextureHandle, bytes: usize, last_used: u64 },
+}
+
+/// Album covers as textures. Lookups that miss are decoded on a worker thread and show up a few frames later.
+pub struct CoverCache {
+    entries: HashMap<AlbumKey, CoverEntry>,
+    requests: Sender<(AlbumKey, PathBuf)>,
+    results: Receiver<(AlbumKey, Option<ColorImage>)>,
+    bytes: usize,
+    frame: u64,
+    ctx: egui::Context,
+}
+
+impl CoverCache {
+    pub fn new(ctx: egui::Context) -> CoverCache {
+        let (requests, request_receiver) = mpsc::channel::<(AlbumKey, PathBuf)>();
+        let (result_sender, results) = mpsc::channel();
+        let repaint_ctx = ctx.clone();
+
+        thread::spawn(move || {
+            let mut stack = Vec::new();
+            while let Ok(request) = request_receiver.recv() {
+                stack.push(request);
+                //newest first, so the albums that just scrolled into view don't wait behind ones that already left it
+                stack.extend(request_receiver.try_iter());
+                while let Some((album, path)) = stack.pop() {
+                    let image = load_cover(&path);
+                    if result_sender.send((album, image)).is_err() {
+                        return;
+                    }
+                    repaint_ctx.request_repaint();
+                    stack.extend(request_receiver.try_iter());
+                }
+            }
+        });
+
+        CoverCache {
+            entries: HashMap::new(),
+            requests,
+            results,
+            bytes: 0,
+            frame: 0,
+            ctx,
+        }
+    }
+
+    /// Cover of `album`, or None while it's still loading or when it has none.
+    /// `song_path` is any song of the album, its tags and folder are searched for the picture.
+    pub fn get(&mut self, album: &AlbumKey, song_path: &Path) -> Option<TextureHandle> {
+        match self.entries.get_mut(album) {
+            Some(CoverEntry::Loaded { texture, last_used, .. }) => {
+                *last_used = self.frame;
+                Some(texture.clone())
+            }
+            Some(_) => None,
+            None => {
+                self.entries.insert(album.clone(), CoverEntry::Pending);
+                let _ = self.requests.send((album.clone(), song_path.to_path_buf()));
+                None
+            }
+        }
+    }
+
+    /// Uploads whatever the worker finished and frees covers that haven't been drawn lately. Call once per frame.
+    pub fn poll(&mut self) {
+        self.frame += 1;
+        while let Ok((album, image)) = self.results.try_recv() {
+            //cleared while it was loading
+            if !matches!(self.entries.get(&album), Some(CoverEntry::Pending)) {
+                continue;
+            }
+            let entry = match image {
+                Some(image) => {
+                    let bytes = image.pixels.len() * 4;
+                    let name = format!("cover {} - {}", album.0, album.1);
+                    let texture = self.ctx.load_texture(name, image, TextureOptions::LINEAR);
+                    self.bytes += bytes;
+                    CoverEntry::Loaded { texture, bytes, last_used: self.frame }
+                }
+                None => CoverEntry::Missing,
+            };
+            self.entries.insert(album, entry);
+        }
+        self.evict();
+    }
+
+    fn evict(&mut self) {
+        if self.bytes <= MAX_TEXTURE_BYTES {
+            return;
+        }
+        let mut loaded: Vec<(u64, AlbumKey)> = self.entries.iter()
+            .filter_map(|(album, entry)| match entry {
+                //covers drawn last frame stay, even if that means going over for a moment
+                CoverEntry::Loaded { last_used, .. } if *last_used + 1 < self.frame => Some((*last_used, album.clone())),
+                _ => None,
+            })
+            .collect();
+        loaded.sort();
+        for (_, album) in loaded {
+            if self.bytes <= MAX_TEXTURE_BYTES {
+                break;
+            }
+            //dropping the entry drops the last handle, which frees the texture
+            if let Some(CoverEntry::Loaded { bytes, .. }) = self.entries.remove(&album) {
+                self.bytes -= bytes;
+            }
+        }
+    }
+
+    /// Forgets every cover, e.g. after a rescan when pictures may have been added or changed.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.bytes = 0;
+    }
+}
+
+//Embedded front cover first, then any embedded picture, then an image in the song's folder.
+fn load_cover(song_path: &Path) -> Option<ColorImage> {
+    let data = embedded_picture(song_path).or_else(|| folder_image(song_path))?;
+    let image = match image::load_from_memory(&data) {
+        Ok(image) => image,
+        Err(e) => {
+            println!("Failed to decode cover for {:?}: {e}", song_path);
+            return None;
+        }
+    };
+    let image = image.thumbnail(COVER_SIZE, COVER_SIZE).to_rgba8();
+    let size = [image.width() as usize, image.height() as usize];
+    Some(ColorImage::from_rgba_unmultiplied(size, image.as_raw()))
+}
+
+fn embedded_picture(song_path: &Path) -> Option<Vec<u8>> {
+    let tagged_file = Probe::open(song_path).ok()?
+        .options(ParseOptions::new().read_properties(false))
+        .read().ok()?;
+    let pictures: Vec<_> = tagged_file.tags().iter().flat_map(|tag| tag.pictures()).collect();
+    pictures.iter()
+        .find(|p| p.pic_type() == PictureType::CoverFront)
+        .or(pictures.first())
+        .map(|p| p.data().to_vec())
+}
+
+fn folder_image(song_path: &Path) -> Option<Vec<u8>> {
+    let folder = song_path.parent()?;
+    //names are matched case-insensitively, Cover.JPG is as common as cover.jpg
+    let mut candidates: Vec<(usize, PathBuf)> = fs::read_dir(folder).ok()?
+        .flatten()
+        .filter_map(|entry| {
+            let path = entry.path();
+            let stem = path.file_stem()?.to_string_lossy().to_lowercase();
+            let extension = path.extension()?.to_string_lossy().to_lowercase();
+            if !FOLDER_IMAGE_EXTENSIONS.contains(&extension.as_str()) {
+                return None;
+            }
+            let rank = FOLDER_IMAGES.iter().position(|name| *name == stem)?;
+            Some((rank, path))
+        })
+        .collect();
+    candidates.sort();
+    candidates.into_iter().find_map(|(_, path)| fs::read(path).ok())
+}
@@ -0,0 +1,191 @@
+use std::{sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex}, time::Duration};
+use rodio::{source::SeekError, ChannelCount, SampleRate, Source};
+
+/// Samples played between looks at the shared fade order, so the audio thread isn't locking every sample.
+const CHECK_INTERVAL: usize = 256;
+
+/// How the volume moves during a crossfade.
+#[derive(Clone, Copy, PartialEq, Default)]
+pub enum FadeCurve {
+    Linear,
+    #[default]
+    EqualPower,     //keeps the combined loudness steady, a linear fade dips in the middle
+}
+
+impl FadeCurve {
+    pub const ALL: [FadeCurve; 2] = [FadeCurve::Linear, FadeCurve::EqualPower];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            FadeCurve::Linear => "Linear",
+            FadeCurve::EqualPower => "Equal power",
+        }
+    }
+
+    pub fn from_label(label: &str) -> Option<FadeCurve> {
+        FadeCurve::ALL.into_iter().find(|c| c.label() == label)
+    }
+
+    //Gain at `t` (0 to 1) into a fade in, a fade out is the same curve played backwards.
+    fn gain(&self, t: f32) -> f32 {
+        match self {
+            FadeCurve::Linear => t,
+            FadeCurve::EqualPower => (t * std::f32::consts::FRAC_PI_2).sin(),
+        }
+    }
+}
+
+struct FadeOrder {
+    duration: Duration,
+    curve: FadeCurve,
+}
+
+/// Lets the UI thread fade out a source that's already playing.
+#[derive(Clone, Default)]
+pub struct FadeHandle {
+    order: Arc<Mutex<Option<FadeOrder>>>,
+    stopped: Arc<AtomicBool>,   //looked at every sample, unlike the order, so a stopped source doesn't get a single one out
+}
+
+impl FadeHandle {
+    /// Ramps the source down to silence over `duration`, after which it ends.
+    pub fn fade_out(&self, duration: Duration, curve: FadeCurve) {
+        *self.order.lock().unwrap() = Some(FadeOrder { duration, curve });
+    }
+
+    /// Ends the source right away, or as soon as it's reached when it's still waiting in the sink.
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+}
+
+struct Ramp {
+    total: u64,     //samples the ramp lasts, counting every channel
+    elapsed: u64,
+    curve: FadeCurve,
+    fading_in: bool,
+    from: f32,      //gain the ramp started at, a fade out can cut a fade in short
+}
+
+impl Ramp {
+    fn new(duration: Duration, curve: FadeCurve, fading_in: bool, from: f32, sample_rate: SampleRate, channels: ChannelCount) -> Ramp {
+        Ramp {
+            //whole frames, a source that ends halfway through one would swap the channels of whatever follows
+            total: (duration.as_secs_f64() * sample_rate as f64) as u64 * channels as u64,
+            elapsed: 0,
+            curve,
+            fading_in,
+            from,
+        }
+    }
+
+    fn gain(&self) -> f32 {
+        let t = if self.total == 0 { 1.0 } else { (self.elapsed as f32 / self.total as f32).min(1.0) };
+        if self.fading_in {
+            self.curve.gain(t)
+        } else {
+            self.from * self.curve.gain(1.0 - t)
+        }
+    }
+
+    fn finished(&self) -> bool {
+        self.elapsed >= self.total
+    }
+}
+
+/// Source wrapper that can fade in when it starts and fade out whenever its `FadeHandle` says so.
+pub struct Fadeable<S> {
+    input: S,
+    handle: FadeHandle,
+    ramp: Option<Ramp>,
+    until_check: usize,
+    frame_position: u16,    //channel the next sample is for, a stopped source ends on a whole frame
+}
+
+impl<S: Source> Fadeable<S> {
+    pub fn new(input: S, handle: FadeHandle) -> Fadeable<S> {
+        Fadeable {
+            input,
+            handle,
+            ramp: None,
+            until_check: 0,
+            frame_position: 0,
+        }
+    }
+
+    /// Starts the source silent and brings it up to full volume over `duration`.
+    pub fn fade_in(mut self, duration: Duration, curve: FadeCurve) -> Fadeable<S> {
+        if !duration.is_zero() {
+            self.ramp = Some(Ramp::new(duration, curve, true, 0.0, self.input.sample_rate(), self.input.channels()));
+        }
+        self
+    }
+
+    fn current_gain(&self) -> f32 {
+        self.ramp.as_ref().map(Ramp::gain).unwrap_or(1.0)
+    }
+}
+
+impl<S: Source> Iterator for Fadeable<S> {
+    type Item = S::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.frame_position == 0 && self.handle.stopped.load(Ordering::Relaxed) {
+            return None;
+        }
+        if self.until_check == 0 {
+            self.until_check = CHECK_INTERVAL;
+            if let Some(order) = self.handle.order.try_lock().ok().and_then(|mut order| order.take()) {
+                let from = self.current_gain();
+                self.ramp = Some(Ramp::new(order.duration, order.curve, false, from, self.input.sample_rate(), self.input.channels()));
+            }
+        }
+        self.until_check -= 1;
+
+        if let Some(ramp) = &self.ramp {
+            if ramp.finished() {
+                if !ramp.fading_in {
+                    return None;
+                }
+                self.ramp = None;
+            }
+        }
+        let sample = self.input.next()?;
+        self.frame_position = (self.frame_position + 1) % self.input.channels().max(1);
+        match &mut self.ramp {
+            Some(ramp) => {
+                let gain = ramp.gain();
+                ramp.elapsed += 1;
+                Some(sample * gain)
+            }
+            None => Some(sample),
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        //a fade out can end the source early
+        (0, self.input.size_hint().1)
+    }
+}
+
+impl<S: Source> Source for Fadeable<S> {
+    fn current_span_len(&self) -> Option<usize> {
+        self.input.current_span_len()
+    }
+
+    fn channels(&self) -> ChannelCount {
+        self.input.channels()
+    }
+
+    fn sample_rate(&self) -> SampleRate {
+        self.input.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.input.total_duration()
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        self.input.try_seek(pos)
+    }
+}
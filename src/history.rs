@@ -0,0 +1,165 @@
+use std::{collections::VecDeque, time::{Duration, Instant, SystemTime}};
+use crate::library::SongId;
+
+/// Entries kept before the oldest ones are dropped.
+const HISTORY_LIMIT: usize = 500;
+
+/// Songs left before this much was heard aren't recorded, skipping past something isn't listening to it.
+const MIN_LISTEN: Duration = Duration::from_secs(1);
+
+#[derive(Clone)]
+pub struct HistoryEntry {
+    pub played_at: SystemTime,
+    pub song: SongId,
+    pub queue_uid: Option<usize>,   //queue entry it was played from, if it came from the queue
+    pub listened: Duration,         //time it was actually playing, pauses and seeks don't count
+}
+
+/// Songs played recently, oldest first.
+#[derive(Default)]
+pub struct PlayHistory {
+    entries: VecDeque<HistoryEntry>,
+    current: Option<HistoryEntry>,  //the song playing now, recorded once something else takes over
+    last_tick: Option<Instant>,
+}
+
+impl PlayHistory {
+    /// Call every frame with what's current. When that changed the previous song goes into the history,
+    /// unless `record` is false. Returns the song that was just recorded, if any.
+    pub fn update(&mut self, song: Option<SongId>, queue_uid: Option<usize>, playing: bool, record: bool) -> Option<SongId> {
+        let elapsed = self.last_tick.map(|t| t.elapsed()).unwrap_or_default();
+        self.last_tick = Some(Instant::now());
+
+        if let Some(current) = self.current.as_mut() {
+            if Some(current.song) == song && current.queue_uid == queue_uid {
+                if playing {
+                    current.listened += elapsed;
+                }
+                return None;
+            }
+        }
+        let mut recorded = None;
+        if let Some(previous) = self.current.take() {
+            if record && previous.listened >= MIN_LISTEN {
+                recorded = Some(previous.song);
+                self.entries.push_back(previous);
+                if self.entries.len() > HISTORY_LIMIT {
+                    self.entries.pop_front();
+                }
+            }
+        }
+        self.current = song.map(|song| HistoryEntry {
+            played_at: SystemTime::now(),
+            song,
+            queue_uid,
+            listened: Duration::ZERO,
+        });
+        recorded
+    }
+
+    /// Takes the most recent entry off the history, for going back to it.
+    pub fn pop(&mut self) -> Option<HistoryEntry> {
+        self.entries.pop_back()
+    }
+
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &HistoryEntry> + ExactSizeIterator {
+        self.entries.iter()
+    }
+
+    pub fn remove(&mut self, index: usize) {
+        self.entries.remove(index);
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// "just now", "5 min ago", "3 h ago" or "2 d ago".
+pub fn format_ago(time: SystemTime) -> String {
+    let secs = SystemTime::now().duration_since(time).unwrap_or_default().as_secs();
+    match secs {
+        0..60 => "just now".to_string(),
+        60..3600 => format!("{} min ago", secs / 60),
+        3600..86400 => format!("{} h ago", secs / 3600),
+        _ => format!("{} d ago", secs / 86400),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::library::{SongInfo, SongStore};
+
+    fn songs(count: usize) -> Vec<SongId> {
+        let mut store = SongStore::default();
+        (0..count).map(|i| store.insert(SongInfo { path: format!("{i}.mp3").into(), ..Default::default() }).0).collect()
+    }
+
+    //stands in for the song having played that long
+    fn listen(history: &mut PlayHistory, time: Duration) {
+        history.current.as_mut().unwrap().listened += time;
+    }
+
+    #[test]
+    fn records_songs_once_they_were_listened_to() {
+        let ids = songs(3);
+        let mut history = PlayHistory::default();
+        assert_eq!(history.update(Some(ids[0]), Some(0), true, true), None);
+        listen(&mut history, MIN_LISTEN);
+        assert_eq!(history.update(Some(ids[0]), Some(0), true, true), None);
+        assert_eq!(history.update(Some(ids[1]), Some(1), true, true), Some(ids[0]));
+        //skipped right away
+        assert_eq!(history.update(Some(ids[2]), Some(2), true, true), None);
+        assert_eq!(history.iter().map(|e| e.song).collect::<Vec<_>>(), [ids[0]]);
+    }
+
+    #[test]
+    fn the_same_song_from_another_queue_entry_counts_again() {
+        let ids = songs(1);
+        let mut history = PlayHistory::default();
+        history.update(Some(ids[0]), Some(0), true, true);
+        listen(&mut history, MIN_LISTEN);
+        assert_eq!(history.update(Some(ids[0]), Some(1), true, true), Some(ids[0]));
+    }
+
+    #[test]
+    fn going_back_isnt_recorded() {
+        let ids = songs(2);
+        let mut history = PlayHistory::default();
+        history.update(Some(ids[0]), None, true, true);
+        listen(&mut history, MIN_LISTEN);
+        assert_eq!(history.update(Some(ids[1]), None, true, false), None);
+        assert!(history.is_empty());
+    }
+
+    #[test]
+    fn pop_remove_and_limit() {
+        let ids = songs(2);
+        let mut history = PlayHistory::default();
+        for i in 0..HISTORY_LIMIT + 10 {
+            history.update(Some(ids[i % 2]), Some(i), true, true);
+            listen(&mut history, MIN_LISTEN);
+        }
+        history.update(None, None, false, true);
+        assert_eq!(history.iter().len(), HISTORY_LIMIT);
+        assert_eq!(history.pop().map(|e| e.queue_uid), Some(Some(HISTORY_LIMIT + 9)));
+        history.remove(0);
+        assert_eq!(history.iter().next().and_then(|e| e.queue_uid), Some(11));
+        history.clear();
+        assert!(history.is_empty());
+    }
+
+    #[test]
+    fn ago() {
+        let now = SystemTime::now();
+        assert_eq!(format_ago(now), "just now");
+        assert_eq!(format_ago(now - Duration::from_secs(5 * 60)), "5 min ago");
+        assert_eq!(format_ago(now - Duration::from_secs(3 * 3600)), "3 h ago");
+        assert_eq!(format_ago(now - Duration::from_secs(2 * 86400)), "2 d ago");
+    }
+}
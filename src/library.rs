@@ -0,0 +1,302 @@
+use std::{path::{Path, PathBuf}, time::{Duration, SystemTime}};
+use egui::ahash::HashMap;
+use serde::{Deserialize, Serialize};
+use crate::{analysis::Loudness, replaygain::ReplayGain};
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SongInfo {
+    pub artist: String,
+    pub track: String,
+    pub album: String,
+    pub path: PathBuf,
+    pub duration: Duration,
+    pub track_number: Option<usize>,
+    pub track_total: Option<usize>,
+    pub album_artist: Option<String>,   //the AlbumArtist tag as written, None when the file doesn't have one
+    pub disc_number: Option<usize>,
+    pub disc_total: Option<usize>,
+    pub year: Option<u32>,
+    pub date: Option<String>,           //full recording date when the tag has more than the year
+    pub genre: Option<String>,
+    pub composer: Option<String>,
+    pub comment: Option<String>,
+    pub rating: Option<u8>,             //stars out of 5
+    pub bitrate: Option<u32>,           //kbps
+    pub sample_rate: Option<u32>,       //Hz
+    pub channels: Option<u8>,
+    pub bit_depth: Option<u8>,
+    pub codec: String,
+    pub replay_gain: ReplayGain,        //from the tags
+    pub loudness: Option<Loudness>,     //measured by the loudness analysis, stands in when the tags have no ReplayGain
+    pub tags_inferred: bool,            //no tags in the file, names were guessed from the path
+    pub file_size: u64,         //size and modification time when scanned, to validate the library cache
+    pub modified: SystemTime,
+    pub added: SystemTime,      //when the song first showed up in the library
+}
+
+impl Default for SongInfo {
+    fn default() -> Self {
+        Self {
+            artist: String::new(),
+            track: String::new(),
+            album: String::new(),
+            path: PathBuf::new(),
+            duration: Duration::ZERO,
+            track_number: None,
+            track_total: None,
+            album_artist: None,
+            disc_number: None,
+            disc_total: None,
+            year: None,
+            date: None,
+            genre: None,
+            composer: None,
+            comment: None,
+            rating: None,
+            bitrate: None,
+            sample_rate: None,
+            channels: None,
+            bit_depth: None,
+            codec: String::new(),
+            replay_gain: ReplayGain::default(),
+            loudness: None,
+            tags_inferred: false,
+            file_size: 0,
+            modified: SystemTime::UNIX_EPOCH,
+            added: SystemTime::UNIX_EPOCH,
+        }
+    }
+}
+
+impl SongInfo {
+    /// One line summary of the technical details, e.g. "FLAC 44.1 kHz 16 bit stereo 913 kbps".
+    pub fn format_summary(&self) -> String {
+        let mut parts = vec![self.codec.clone()];
+        if let Some(rate) = self.sample_rate {
+            parts.push(format!("{} kHz", rate as f32 / 1000.0));
+        }
+        if let Some(depth) = self.bit_depth {
+            parts.push(format!("{} bit", depth));
+        }
+        match self.channels {
+            Some(1) => parts.push("mono".to_string()),
+            Some(2) => parts.push("stereo".to_string()),
+            Some(n) => parts.push(format!("{} ch", n)),
+            None => {}
+        }
+        if let Some(bitrate) = self.bitrate {
+            parts.push(format!("{} kbps", bitrate));
+        }
+        parts.push(format!("{:.1} MB", self.file_size as f64 / (1024.0 * 1024.0)));
+        parts.join(" ")
+    }
+
+    /// ReplayGain to play the song with, the tags win over a measurement of our own.
+    pub fn gain(&self) -> ReplayGain {
+        match &self.loudness {
+            Some(loudness) if self.replay_gain.is_empty() => loudness.replay_gain(),
+            _ => self.replay_gain,
+        }
+    }
+
+    /// Multi-line description used for hover text in the library and queue.
+    pub fn details(&self) -> String {
+        let mut lines = vec![format!("{} - {}", self.artist, self.track)];
+        if !self.album.is_empty() {
+            lines.push(format!("Album: {}", self.album));
+        }
+        if let Some(album_artist) = &self.album_artist {
+            lines.push(format!("Album artist: {}", album_artist));
+        }
+        match (self.disc_number, self.disc_total) {
+            (Some(disc), Some(total)) => lines.push(format!("Disc {} of {}", disc, total)),
+            (Some(disc), None) => lines.push(format!("Disc {}", disc)),
+            _ => {}
+        }
+        if let Some(date) = self.date.clone().or(self.year.map(|y| y.to_string())) {
+            lines.push(format!("Date: {}", date));
+        }
+        if let Some(genre) = &self.genre {
+            lines.push(format!("Genre: {}", genre));
+        }
+        if let Some(composer) = &self.composer {
+            lines.push(format!("Composer: {}", composer));
+        }
+        if let Some(comment) = &self.comment {
+            lines.push(format!("Comment: {}", comment));
+        }
+        if let Some(rating) = self.rating {
+            lines.push(format!("Rating: {}{}", "★".repeat(rating as usize), "☆".repeat(5 - rating as usize)));
+        }
+        if !self.gain().is_empty() {
+            lines.push(format!("ReplayGain: {}", self.gain().summary()));
+        }
+        if let Some(loudness) = &self.loudness {
+            lines.push(format!("Loudness: {:.1} LUFS, true peak {:.1} dBTP", loudness.integrated, 20.0 * loudness.true_peak.log10()));
+        }
+        lines.push(self.format_summary());
+        lines.push(self.path.to_string_lossy().to_string());
+        lines.join("\n")
+    }
+}
+
+/// Handle to a song in the `SongStore`. Ids are never reused, so one that outlives its song
+/// just stops resolving instead of pointing at whatever took its place.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct SongId(u64);
+
+/// (album title, album artist)
+pub type AlbumKey = (String, String);
+
+pub const VARIOUS_ARTISTS: &str = "Various Artists";
+
+#[derive(Clone, Default)]
+pub struct AlbumInfo{
+    pub songs: Vec<SongId>  //sorted by disc, then track number
+}
+
+impl AlbumInfo {
+    /// More than one disc number among the songs, worth showing disc headers for.
+    pub fn is_multi_disc(&self, store: &SongStore) -> bool {
+        let mut discs = self.songs.iter().filter_map(|id| store.get(*id)).map(|s| s.disc_number.unwrap_or(1));
+        let first = discs.next();
+        discs.any(|d| Some(d) != first)
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct LibraryInfo{
+    pub albums: HashMap<AlbumKey, AlbumInfo>,
+    pub song_album: HashMap<SongId, AlbumKey>,  //which album each song was grouped into
+}
+
+impl LibraryInfo {
+    /// Groups every song into albums. Songs with an AlbumArtist tag use it, the rest are grouped per folder
+    /// and album title, and a group whose tracks disagree on the artist is treated as a compilation.
+    pub fn rebuild(store: &SongStore) -> LibraryInfo {
+        let mut untagged: HashMap<(PathBuf, String), Vec<SongId>> = HashMap::default();
+        let mut library = LibraryInfo::default();
+
+        for (id, song) in store.iter() {
+            match song.album_artist.as_ref().map(|a| a.trim()).filter(|a| !a.is_empty()) {
+                Some(album_artist) => library.insert((song.album.clone(), album_artist.to_string()), id),
+                None => {
+                    let folder = song.path.parent().map(Path::to_path_buf).unwrap_or_default();
+                    untagged.entry((folder, song.album.clone())).or_default().push(id);
+                }
+            }
+        }
+
+        for ((_, album), songs) in untagged {
+            let mut artists = songs.iter().filter_map(|id| store.get(*id)).map(|s| primary_artist(&s.artist));
+            let first = artists.next().unwrap_or_default();
+            let album_artist = if artists.all(|a| a.eq_ignore_ascii_case(&first)) { first } else { VARIOUS_ARTISTS.to_string() };
+            for id in songs {
+                library.insert((album.clone(), album_artist.clone()), id);
+            }
+        }
+
+        for album in library.albums.values_mut() {
+            album.songs.sort_by_key(|id| {
+                let song = store.get(*id);
+                (
+                    song.and_then(|s| s.disc_number).unwrap_or(1),
+                    song.and_then(|s| s.track_number).unwrap_or(usize::MAX),
+                    song.map(|s| s.track.clone()).unwrap_or_default(),
+                )
+            });
+        }
+        library
+    }
+
+    fn insert(&mut self, key: AlbumKey, id: SongId) {
+        self.song_album.insert(id, key.clone());
+        self.albums.entry(key).or_default().songs.push(id);
+    }
+}
+
+/// First name of a credit like "Artist, Guest & Other", so featured artists don't split an album up.
+fn primary_artist(artist: &str) -> String {
+    artist.split([',', '&', '/']).map(|s| s.trim()).find(|s| !s.is_empty()).unwrap_or(artist).to_string()
+}
+
+/// Order of the albums in the library tree.
+#[derive(Clone, Copy, PartialEq, Default)]
+pub enum LibrarySort {
+    #[default]
+    Album,
+    Artist,
+    Year,
+    Genre,
+}
+
+impl LibrarySort {
+    pub const ALL: [LibrarySort; 4] = [LibrarySort::Album, LibrarySort::Artist, LibrarySort::Year, LibrarySort::Genre];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            LibrarySort::Album => "Album",
+            LibrarySort::Artist => "Artist",
+            LibrarySort::Year => "Year",
+            LibrarySort::Genre => "Genre",
+        }
+    }
+
+    pub fn from_label(label: &str) -> Option<LibrarySort> {
+        LibrarySort::ALL.into_iter().find(|s| s.label() == label)
+    }
+}
+
+/// Every song the player knows about, addressed by `SongId` and looked up by path when files change.
+#[derive(Default)]
+pub struct SongStore {
+    songs: HashMap<SongId, SongInfo>,
+    by_path: HashMap<PathBuf, SongId>,
+    next_id: u64,
+}
+
+impl SongStore {
+    pub fn get(&self, id: SongId) -> Option<&SongInfo> {
+        self.songs.get(&id)
+    }
+
+    pub fn get_mut(&mut self, id: SongId) -> Option<&mut SongInfo> {
+        self.songs.get_mut(&id)
+    }
+
+    pub fn id_for_path(&self, path: &Path) -> Option<SongId> {
+        self.by_path.get(path).copied()
+    }
+
+    /// Adds a song, or replaces the one with the same path so it keeps its id.
+    /// Returns the id along with the info it replaced.
+    pub fn insert(&mut self, song: SongInfo) -> (SongId, Option<SongInfo>) {
+        if let Some(id) = self.id_for_path(&song.path) {
+            let old = self.songs.insert(id, song);
+            return (id, old);
+        }
+        let id = SongId(self.next_id);
+        self.next_id += 1;
+        self.by_path.insert(song.path.clone(), id);
+        self.songs.insert(id, song);
+        (id, None)
+    }
+
+    pub fn remove(&mut self, id: SongId) -> Option<SongInfo> {
+        let song = self.songs.remove(&id)?;
+        self.by_path.remove(&song.path);
+        Some(song)
+    }
+
+    pub fn contains(&self, id: SongId) -> bool {
+        self.songs.contains_key(&id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (SongId, &SongInfo)> {
+        self.songs.iter().map(|(id, song)| (*id, song))
+    }
+
+    pub fn ids(&self) -> impl Iterator<Item = SongId> + '_ {
+        self.songs.keys().copied()
+    }
+}
@@ -0,0 +1,239 @@
+use std::{fs, path::{Component, Path, PathBuf}, time::Duration};
+
+/// One entry read from an M3U playlist.
+pub struct M3uEntry {
+    pub path: PathBuf,              //relative entries are already resolved against the playlist's folder
+    pub title: Option<String>,      //from #EXTINF, usually "Artist - Title"
+    pub duration: Option<Duration>, //from #EXTINF, None when it's missing or -1
+}
+
+/// What's written for each song when exporting.
+pub struct M3uTrack<'a> {
+    pub path: &'a Path,
+    pub title: String,
+    pub duration: Duration,
+}
+
+/// Reads an .m3u or .m3u8 file. Lines that aren't local files, like stream URLs, are skipped.
+pub fn read(path: &Path) -> Result<Vec<M3uEntry>, String> {
+    let bytes = fs::read(path).map_err(|e| e.to_string())?;
+    let base = path.parent().unwrap_or(Path::new(""));
+    Ok(parse(&decode(&bytes), base))
+}
+
+/// Entries of a playlist's text, relative paths are taken from `base`.
+pub fn parse(text: &str, base: &Path) -> Vec<M3uEntry> {
+    let mut entries = Vec::new();
+    let mut extinf: Option<(Option<Duration>, Option<String>)> = None;
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(info) = line.strip_prefix("#EXTINF:") {
+            extinf = Some(parse_extinf(info));
+            continue;
+        }
+        if line.starts_with('#') {
+            continue;
+        }
+        let (duration, title) = extinf.take().unwrap_or_default();
+        let Some(path) = entry_path(line) else {
+            continue;
+        };
+        let path = if path.is_absolute() { path } else { base.join(path) };
+        entries.push(M3uEntry {
+            path: normalize(&path),
+            title,
+            duration,
+        });
+    }
+    entries
+}
+
+/// Writes an extended M3U with UTF-8 text. With `relative` set, paths are written relative to the playlist's
+/// folder so the playlist keeps working when the music folder is moved along with it.
+pub fn write(path: &Path, tracks: &[M3uTrack], relative: bool) -> Result<(), String> {
+    let base = path.parent().unwrap_or(Path::new(""));
+    let mut text = String::from("#EXTM3U\n");
+    for track in tracks {
+        let entry = match relative_path(track.path, base).filter(|_| relative) {
+            //forward slashes, which players on every system understand
+            Some(relative) => relative.components().map(|c| c.as_os_str().to_string_lossy()).collect::<Vec<_>>().join("/"),
+            None => track.path.to_string_lossy().to_string(),
+        };
+        text.push_str(&format!("#EXTINF:{},{}\n{}\n", track.duration.as_secs(), track.title, entry));
+    }
+    fs::write(path, text).map_err(|e| e.to_string())
+}
+
+//"123,Artist - Title", attributes some players put before the comma are ignored
+fn parse_extinf(info: &str) -> (Option<Duration>, Option<String>) {
+    let (head, title) = info.split_once(',').unwrap_or((info, ""));
+    let seconds = head.split_whitespace().next().and_then(|s| s.parse::<f64>().ok());
+    let duration = seconds.filter(|s| *s >= 0.0).map(Duration::from_secs_f64);
+    let title = Some(title.trim()).filter(|t| !t.is_empty()).map(|t| t.to_string());
+    (duration, title)
+}
+
+//Plain paths and file:// URLs, anything else is a stream or some other URL we can't play from the library.
+fn entry_path(line: &str) -> Option<PathBuf> {
+    if let Some(url) = line.strip_prefix("file://") {
+        //file:///C:/Music on Windows, the host part is empty
+        let url = if cfg!(windows) { url.trim_start_matches('/') } else { url };
+        return Some(PathBuf::from(percent_decode(url)));
+    }
+    if line.contains("://") {
+        return None;
+    }
+    //playlists made on Windows use backslashes, which are just part of the name elsewhere
+    if cfg!(windows) {
+        Some(PathBuf::from(line))
+    } else {
+        Some(PathBuf::from(line.replace('\\', "/")))
+    }
+}
+
+//Resolves . and .. without touching the disk, library paths are compared as they were scanned.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !normalized.pop() {
+                    normalized.push("..");
+                }
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+//`path` relative to the folder `base`, None when they don't share a root, like files on another drive.
+fn relative_path(path: &Path, base: &Path) -> Option<PathBuf> {
+    let path: Vec<Component> = path.components().collect();
+    let base: Vec<Component> = base.components().collect();
+    if path.first() != base.first() {
+        return None;
+    }
+    let common = path.iter().zip(&base).take_while(|(a, b)| a == b).count();
+    let mut relative = PathBuf::new();
+    for _ in common..base.len() {
+        relative.push("..");
+    }
+    for component in &path[common..] {
+        relative.push(component);
+    }
+    Some(relative)
+}
+
+//.m3u8 is UTF-8 by definition, plain .m3u is often in the system's legacy encoding. Latin-1 gets
+//the common accented characters right where UTF-8 fails.
+fn decode(bytes: &[u8]) -> String {
+    let bytes = bytes.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(bytes);
+    match std::str::from_utf8(bytes) {
+        Ok(text) => text.to_string(),
+        Err(_) => bytes.iter().map(|b| *b as char).collect(),
+    }
+}
+
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| text.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_extinf_and_resolves_relative_paths() {
+        let base = std::env::temp_dir().join("Playlists");
+        let text = "#EXTM3U\n#EXTINF:123,Artist - Title\n../Music/a.mp3\n\n#EXTINF:-1,\nhttp://radio.example/stream\nb.flac\n";
+        let entries = parse(text, &base);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].path, std::env::temp_dir().join("Music").join("a.mp3"));
+        assert_eq!(entries[0].title.as_deref(), Some("Artist - Title"));
+        assert_eq!(entries[0].duration, Some(Duration::from_secs(123)));
+        //the stream's #EXTINF doesn't carry over to the next file
+        assert_eq!(entries[1].path, base.join("b.flac"));
+        assert_eq!(entries[1].title, None);
+        assert_eq!(entries[1].duration, None);
+    }
+
+    #[test]
+    fn extinf_attributes_and_missing_durations() {
+        assert_eq!(parse_extinf("42 tvg-id=\"x\",A - B"), (Some(Duration::from_secs(42)), Some("A - B".to_string())));
+        assert_eq!(parse_extinf("-1,Stream"), (None, Some("Stream".to_string())));
+        assert_eq!(parse_extinf("abc"), (None, None));
+    }
+
+    #[test]
+    fn percent_decoding() {
+        assert_eq!(percent_decode("My%20Music/Caf%C3%A9.mp3"), "My Music/Café.mp3");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz%4"), "%zz%4");
+    }
+
+    #[test]
+    fn decodes_bom_and_latin1() {
+        assert_eq!(decode(b"\xEF\xBB\xBFabc"), "abc");
+        assert_eq!(decode(b"Caf\xE9"), "Café");
+    }
+
+    #[test]
+    fn normalizes_dots() {
+        assert_eq!(normalize(Path::new("a/./b/../c")), PathBuf::from("a/c"));
+        assert_eq!(normalize(Path::new("../a")), PathBuf::from("../a"));
+    }
+
+    #[test]
+    fn relative_paths() {
+        let root = std::env::temp_dir();
+        let song = root.join("Music").join("Artist").join("a.mp3");
+        assert_eq!(relative_path(&song, &root.join("Music")), Some(PathBuf::from("Artist/a.mp3")));
+        assert_eq!(relative_path(&song, &root.join("Playlists")), Some(PathBuf::from("../Music/Artist/a.mp3")));
+        assert_eq!(relative_path(Path::new("relative.mp3"), &root), None);
+    }
+
+    #[test]
+    fn written_playlists_read_back() {
+        let dir = std::env::temp_dir().join(format!("coral_m3u_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let song = dir.join("Music").join("a b.mp3");
+        let track = M3uTrack { path: &song, title: "Artist - Title".to_string(), duration: Duration::from_secs(61) };
+        for relative in [false, true] {
+            let list = dir.join("Lists").join("list.m3u8");
+            fs::create_dir_all(list.parent().unwrap()).unwrap();
+            write(&list, std::slice::from_ref(&track), relative).unwrap();
+            let text = fs::read_to_string(&list).unwrap();
+            assert_eq!(text.contains("../Music/a b.mp3"), relative);
+            let entries = read(&list).unwrap();
+            assert_eq!(entries.len(), 1);
+            assert_eq!(entries[0].path, song);
+            assert_eq!(entries[0].title.as_deref(), Some("Artist - Title"));
+            assert_eq!(entries[0].duration, Some(Duration::from_secs(61)));
+        }
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -1,15 +1,35 @@
 #![windows_subsystem = "windows"]
-use rodio::{Decoder, OutputStream, Sink, Source};
+use rodio::{source::Amplify, Decoder, OutputStream, Sink, Source};
 use egui::{ahash::HashMap, Color32, IconData, TextEdit, ViewportBuilder};
 use egui_dnd::{self};
 use eframe::{egui, Storage, NativeOptions};
 use core::{f32};
-use lofty::{tag, probe::Probe, file::TaggedFileExt};
 use image::GenericImageView;
-use std::{fs::File, io::BufReader, path::PathBuf, sync::Arc, time::Duration};
-use rand::{rng, seq::SliceRandom};
+use std::{collections::HashSet, fs::File, path::PathBuf, sync::Arc, time::{Duration, Instant}};
 use discord_rich_presence::{activity::{self, Assets}, DiscordIpc, DiscordIpcClient};
-
+use serde::{Deserialize, Serialize};
+
+mod analysis;
+mod cache;
+mod covers;
+mod fade;
+mod history;
+mod library;
+mod m3u;
+mod pattern;
+mod playlists;
+mod replaygain;
+mod scan;
+mod selection;
+mod shuffle;
+mod smart;
+mod watch;
+
+use library::{AlbumInfo, AlbumKey, LibraryInfo, LibrarySort, SongId, SongInfo, SongStore};
+use playlists::Playlists;
+use selection::Selection;
+use shuffle::{ShuffleContext, ShuffleMode};
+use smart::{RuleContext, SmartPlaylist};
 
 fn main() -> eframe::Result {
 
@@ -32,52 +52,182 @@ fn main() -> eframe::Result {
     return eframe::run_native("Coral", options, Box::new(|cc| Ok(Box::new(PlayerApp::new(cc)))));
 }
 
-#[derive(Clone, Default)]
-struct SongInfo {
-    artist: String,
-    track: String,
-    album: String,
-    path: PathBuf,
-    duration: Duration,
-    track_number: Option<usize>
+#[derive(Clone, Default, Hash)]
+struct QueueEntry{
+    song: SongId,
+    uid: usize,
+    from_album: bool    //queued as part of a whole album, auto ReplayGain uses the album gain for it
 }
 
-#[derive(Clone, Default)]
-struct AlbumInfo{
-    songs: Vec<usize>
+/// List the selection shortcuts apply to, whichever was clicked in last.
+#[derive(Clone, Copy, PartialEq, Default)]
+enum SelectionFocus {
+    #[default]
+    Library,
+    Queue,
 }
 
-#[derive(Clone, Default)]
-struct LibraryInfo{
-    albums: HashMap<(String, String), AlbumInfo>
+/// What an "Add to Playlist" menu was clicked for.
+#[derive(Clone, Copy)]
+enum PlaylistPick {
+    New,
+    Existing(u64),
 }
 
-#[derive(Clone, Default, Hash)]
-struct QueueEntry{
-    song_index: usize,
-    uid: usize
+/// Things done to a playlist from its context menu, applied once the sidebar is done drawing.
+#[derive(Clone, Copy)]
+enum PlaylistAction {
+    Play,
+    Append,
+    Rename,
+    Duplicate,
+    Export,
+    Delete,
+    Edit,       //smart playlists only, their name is part of the rule editor
+}
+
+/// Smart playlist being edited, changes only apply on Save.
+struct SmartEditor {
+    playlist: SmartPlaylist,
+    is_new: bool,
+    matches: Option<usize>,     //songs the rules match as they are, None when they changed since it was counted
+}
+
+/// The queue as it's kept between sessions. Songs are stored by path, ids only last as long as the app runs.
+#[derive(Default, Serialize, Deserialize)]
+struct SavedQueue {
+    entries: Vec<(PathBuf, bool)>,  //path and from_album of each entry
+    position: usize,
+    seek_secs: f32,
+    shuffled: bool,
+    #[serde(default)]
+    unshuffled: Vec<usize>,         //indices into entries in the order they had before the shuffle
+}
+
+/// What happens when a song ends.
+#[derive(Clone, Copy, PartialEq, Default)]
+enum RepeatMode {
+    #[default]
+    Off,
+    Queue,      //start over from the top once the last entry finished
+    One,        //play the current entry again, ⏭ and ⏮ still move through the queue
+}
+
+impl RepeatMode {
+    const ALL: [RepeatMode; 3] = [RepeatMode::Off, RepeatMode::Queue, RepeatMode::One];
+
+    fn label(&self) -> &'static str {
+        match self {
+            RepeatMode::Off => "Repeat off",
+            RepeatMode::Queue => "Repeat queue",
+            RepeatMode::One => "Repeat one",
+        }
+    }
+
+    fn from_label(label: &str) -> Option<RepeatMode> {
+        RepeatMode::ALL.into_iter().find(|m| m.label() == label)
+    }
+
+    fn icon(&self) -> &'static str {
+        match self {
+            RepeatMode::One => "🔂",
+            _ => "🔁",
+        }
+    }
+
+    fn cycle(&self) -> RepeatMode {
+        match self {
+            RepeatMode::Off => RepeatMode::Queue,
+            RepeatMode::Queue => RepeatMode::One,
+            RepeatMode::One => RepeatMode::Off,
+        }
+    }
+}
+
+//The queue entry appended to the sink behind the current song, so it starts without a gap.
+struct Preload {
+    uid: usize,
+    appended: bool,             //false when the file couldn't be opened, play_next gets to report it
+    fade: fade::FadeHandle,     //stopped when the queue changed under it, so it ends the moment it would start
 }
 
+/// Longest fade used when skipping with ⏭ while crossfade is on, a manual skip shouldn't linger.
+const SKIP_FADE_SECS: f32 = 1.5;
+
 struct PlayerApp {
     playing: bool,
     volume: f32,
-    song_info: Vec<SongInfo>,               //list of all songs recognized by player, paths, names durations, cached
-    song_current_position: Option<usize>,   //index of the track currently playing, None when nothing playing
+    song_info: SongStore,                   //all songs recognized by player, paths, names durations, cached
+    current_song: Option<SongId>,           //the track currently playing, None when nothing playing
     
-    queue_indices: Vec<QueueEntry>,         //list of indices into cached song info, for quicker deletion/addition and no duplicated info
+    queue_indices: Vec<QueueEntry>,         //list of ids into song info, for quicker deletion/addition and no duplicated info
     queue_next_uid: usize,                  //uid for egui_dnd's sorting
     queue_current_position: usize,          //index into the list of indices, stores where we are in that queue of indices// None when queue is empty and nothing playing
     progress: f32, // 0.0–1.0
+    repeat: RepeatMode,
+    shuffled: bool,                         //the queue order came from a shuffle
+    unshuffled: Vec<usize>,                 //uids in the order they had before the first shuffle, for un-shuffling
+    pending_restore: Option<SavedQueue>,    //last session's queue, restored once the first scan has found its songs
+    history: history::PlayHistory,
+    leaving_via_back: bool,                 //the song being left was left with ⏮, it's "forward" of the history now
+    show_history: bool,
+    library_selection: Selection<SongId>,
+    library_order: Vec<SongId>,             //filtered songs as the library listed them last frame, for ranges and select all
+    queue_selection: Selection<usize>,      //by entry uid
+    queue_dragged: Option<(usize, Vec<usize>)>, //uid of the entry being dragged and the selection it takes along, in queue order
+    selection_focus: SelectionFocus,
+    shuffle_mode: ShuffleMode,
+    m3u_relative: bool,                     //exported playlists use paths relative to where they're saved
+    import_report: Option<String>,          //songs of an imported playlist that couldn't be found, shown until dismissed
+    playlists: Playlists,
+    show_playlists: bool,
+    open_playlist: Option<u64>,             //playlist the sidebar lists the songs of
+    renaming_playlist: Option<(u64, String)>,
+    deleting_playlist: Option<u64>,         //waiting for the delete to be confirmed
+    smart_results: HashMap<u64, Vec<SongId>>,   //songs each smart playlist matched when last evaluated
+    smart_dirty: bool,                      //library, play counts or rules changed since
+    editing_smart: Option<SmartEditor>,
+    play_counts: HashMap<PathBuf, u32>,     //times each song made it into the history, by path so rescans keep them
+    open_failed: bool,                      //the last song couldn't be opened, repeating it would only fail again
+    waiting_for_play: bool,                 //the sink was left empty while paused, update() doesn't move on until ▶
+    preloaded: Option<Preload>,             //next entry already waiting in the sink, None when nothing is
+    cancelled_preloads: usize,              //cancelled sources still sitting in the sink behind the current one
+    current_fade: fade::FadeHandle,         //fades out the song playing in audio_sink
+    fading_sinks: Vec<Sink>,                //songs fading out under the current one, dropped once they ran out
+    crossfade_secs: f32,                    //0 plays gapless
+    crossfade_curve: fade::FadeCurve,
+    crossfade_skip_album: bool,             //tracks of the same album follow each other gapless instead
+    crossfaded_from: Option<usize>,         //uid of the entry a crossfade was last started from, so a failed one isn't retried every frame
+    gain_mode: replaygain::GainMode,
+    prevent_clipping: bool,                 //caps ReplayGain so the peak stays below full scale
+    analysis_job: Option<analysis::AnalysisJob>,   //loudness analysis running in the background, None when idle
+    analysis_failed: HashSet<SongId>,       //songs that couldn't be decoded, left out of their album's loudness
+    analysis_writeback: bool,               //write measured ReplayGain into the tags of songs that have none
+    show_playback: bool,
     
     filter_text: String,
-    enabled_album: HashMap<(String, String), bool>,
+    library_sort: LibrarySort,
+    enabled_album: HashMap<AlbumKey, bool>,
     show_dirs: bool,
     dirs: Vec<PathBuf>,
+    scan_options: scan::ScanOptions,
+    scan_job: Option<scan::ScanJob>,        //scan running in the background, None when idle
+    pending_scan_roots: Vec<PathBuf>,       //directories added while a scan was already running
+    rescan_found: Option<HashSet<PathBuf>>, //songs a full rescan came across so far, None outside of one
+    scan_errors: Vec<scan::ScanError>,      //files that failed to scan, replaced per directory whenever it's rescanned
+    show_scan_errors: bool,
+    show_unsupported_files: bool,           //list files that simply aren't audio in the error report too
+    show_tag_patterns: bool,
+    pattern_test_path: String,              //path typed into the pattern tester
+    watcher: Option<watch::LibraryWatcher>, //reports changes in dirs, None if the platform watcher failed to start
+    covers: Option<covers::CoverCache>,     //created in new() once there's an egui context to upload to
     library: LibraryInfo,
+    albums_dirty: bool,                     //songs changed since the album map was last built
+    albums_rebuilt_at: Instant,
 
     discord_client: DiscordIpcClient,
 
-    _output_stream: OutputStream,
+    output_stream: OutputStream,
     audio_sink: Sink
 }
 
@@ -92,18 +242,67 @@ impl PlayerApp {
             if let Some(vol) = storage.get_string("vol"){
                 s.volume = vol.parse::<f32>().unwrap();
             }
+            if let Some(depth) = storage.get_string("scan_depth").and_then(|d| d.parse::<usize>().ok()){
+                s.scan_options.max_depth = depth;
+            }
+            if let Some(hidden) = storage.get_string("scan_hidden").and_then(|h| h.parse::<bool>().ok()){
+                s.scan_options.skip_hidden = hidden;
+            }
+            if let Some(patterns) = storage.get_string("tag_patterns"){
+                s.scan_options.tag_patterns = patterns.lines().map(|l| l.to_string()).collect();
+            }
+            if let Some(secs) = storage.get_string("crossfade").and_then(|c| c.parse::<f32>().ok()){
+                s.crossfade_secs = secs.clamp(0.0, 12.0);
+            }
+            if let Some(curve) = storage.get_string("crossfade_curve").and_then(|c| fade::FadeCurve::from_label(&c)){
+                s.crossfade_curve = curve;
+            }
+            if let Some(skip) = storage.get_string("crossfade_skip_album").and_then(|c| c.parse::<bool>().ok()){
+                s.crossfade_skip_album = skip;
+            }
+            if let Some(mode) = storage.get_string("replaygain").and_then(|m| replaygain::GainMode::from_label(&m)){
+                s.gain_mode = mode;
+            }
+            if let Some(clip) = storage.get_string("replaygain_clip").and_then(|c| c.parse::<bool>().ok()){
+                s.prevent_clipping = clip;
+            }
+            if let Some(writeback) = storage.get_string("analysis_writeback").and_then(|w| w.parse::<bool>().ok()){
+                s.analysis_writeback = writeback;
+            }
+            if let Some(repeat) = storage.get_string("repeat").and_then(|r| RepeatMode::from_label(&r)){
+                s.repeat = repeat;
+            }
+            if let Some(mode) = storage.get_string("shuffle_mode").and_then(|m| ShuffleMode::from_label(&m)){
+                s.shuffle_mode = mode;
+            }
+            if let Some(relative) = storage.get_string("m3u_relative").and_then(|r| r.parse::<bool>().ok()){
+                s.m3u_relative = relative;
+            }
+            if let Some(show) = storage.get_string("show_playlists").and_then(|p| p.parse::<bool>().ok()){
+                s.show_playlists = show;
+            }
+            if let Some(playlists) = storage.get_string("playlists") {
+                match Playlists::load(&playlists) {
+                    Ok(playlists) => s.playlists = playlists,
+                    Err(e) => println!("Failed to load playlists: {e}"),
+                }
+            }
+            if let Some(counts) = storage.get_string("play_counts").and_then(|c| ron::from_str(&c).ok()){
+                s.play_counts = counts;
+            }
+            s.pending_restore = storage.get_string("queue").and_then(|q| ron::from_str::<SavedQueue>(&q).ok());
+            if let Some(sort) = storage.get_string("library_sort").and_then(|l| LibrarySort::from_label(&l)){
+                s.library_sort = sort;
+            }
         }
 
-        for dir in s.dirs.clone(){
-            s.scan_folder(dir);
-        }
-        for album in s.library.albums.clone(){
-            let mut album_songs = album.1.songs.clone();
-
-            album_songs.sort_by(|a, b| {
-                s.song_info[*a].track_number.unwrap_or(usize::MAX).cmp(&s.song_info[*b].track_number.unwrap_or(usize::MAX))
-            });
-            s.library.albums.entry(album.0).and_modify(|a| a.songs = album_songs);
+        s.covers = Some(covers::CoverCache::new(cc.egui_ctx.clone()));
+        s.scan_folders(s.dirs.clone(), &cc.egui_ctx);
+        s.watcher = watch::LibraryWatcher::new(cc.egui_ctx.clone());
+        if let Some(watcher) = s.watcher.as_mut(){
+            for dir in &s.dirs {
+                watcher.watch(dir);
+            }
         }
 
         let mut fonts = egui::FontDefinitions::default();
@@ -117,82 +316,828 @@ impl PlayerApp {
         s
     }
 
-    fn queue_song_from_file(&mut self, filename: PathBuf){
-        let file = File::open(filename);
-        if file.is_ok() {
-            let dec = Decoder::try_from(file.unwrap()).unwrap();
-            self.audio_sink.append(dec);
+    //Opens a song with its ReplayGain already applied, so the sink's volume stays the user's own.
+    fn open_song(&self, id: SongId, from_album: bool) -> Result<Amplify<Decoder<std::io::BufReader<File>>>, String>{
+        let song = self.song_info.get(id).ok_or("song is no longer in the library")?;
+        let dec = File::open(&song.path).map_err(|e| e.to_string())
+            .and_then(|f| Decoder::try_from(f).map_err(|e| e.to_string()))
+            .map_err(|e| format!("{:?}: {e}", song.path))?;
+        Ok(dec.amplify(song.gain().factor(self.gain_mode, from_album, self.prevent_clipping)))
+    }
+
+    fn queue_song(&mut self, id: SongId, from_album: bool){
+        self.waiting_for_play = false;
+        //on failure the sink stays empty and update() moves on to the next queue entry
+        match self.open_song(id, from_album) {
+            Ok(source) => {
+                self.current_fade = fade::FadeHandle::default();
+                self.audio_sink.append(fade::Fadeable::new(source, self.current_fade.clone()));
+                self.open_failed = false;
+            }
+            Err(e) => {
+                println!("Failed to play {e}");
+                self.open_failed = true;
+            }
+        }
+    }
+
+    //Empties the sink, including whatever was preloaded behind the current song and anything still fading out.
+    fn clear_sink(&mut self){
+        self.preloaded = None;
+        self.cancelled_preloads = 0;
+        self.crossfaded_from = None;
+        self.fading_sinks.clear();
+        self.audio_sink.clear();
+    }
+
+    //Queue position that follows the current one, None when the queue ends there. `manual` is for ⏭,
+    //which moves on even when repeating one song.
+    fn next_position(&self, manual: bool) -> Option<usize>{
+        if self.queue_indices.is_empty(){
+            return None;
+        }
+        if self.repeat == RepeatMode::One && !manual && self.queue_current_position < self.queue_indices.len() {
+            return Some(self.queue_current_position);
+        }
+        if self.queue_current_position + 1 < self.queue_indices.len() {
+            return Some(self.queue_current_position + 1);
+        }
+        (self.repeat == RepeatMode::Queue).then_some(0)
+    }
+
+    //Whether moving on to `next` fades into it rather than following on gapless.
+    fn crossfades_into(&self, next: &QueueEntry) -> bool{
+        if self.crossfade_secs <= 0.0 {
+            return false;
+        }
+        //a song repeating into itself loops seamlessly
+        if self.queue_indices.get(self.queue_current_position).is_some_and(|current| current.uid == next.uid) {
+            return false;
+        }
+        if !self.crossfade_skip_album {
+            return true;
+        }
+        let album = |id: SongId| self.library.song_album.get(&id);
+        !self.current_song.is_some_and(|current| album(current).is_some() && album(current) == album(next.song))
+    }
+
+    //Moves the current song into a sink of its own to fade out there and fades the next queue entry in over it,
+    //both sinks play through the same output mixer. Returns false when the next entry couldn't be opened.
+    fn crossfade_to_next(&mut self, fade_length: Duration, manual: bool) -> bool{
+        let Some(position) = self.next_position(manual) else {
+            return false;
+        };
+        let next = self.queue_indices[position].clone();
+        let source = match self.open_song(next.song, next.from_album) {
+            Ok(source) => source,
+            Err(e) => {
+                println!("Failed to play {e}");
+                return false;
+            }
+        };
+
+        //a preloaded entry would start playing in the old sink once the fade ends
+        if let Some(preload) = self.preloaded.take().filter(|p| p.appended) {
+            preload.fade.stop();
+        }
+        self.cancelled_preloads = 0;
+        self.current_fade.fade_out(fade_length, self.crossfade_curve);
+        let incoming = Sink::connect_new(self.output_stream.mixer());
+        incoming.set_volume(self.volume);
+        self.fading_sinks.push(std::mem::replace(&mut self.audio_sink, incoming));
+
+        self.current_fade = fade::FadeHandle::default();
+        self.audio_sink.append(fade::Fadeable::new(source, self.current_fade.clone()).fade_in(fade_length, self.crossfade_curve));
+        self.audio_sink.play();
+        self.queue_current_position = position;
+        self.current_song = Some(next.song);
+        self.playing = true;
+        self.progress = 0.0;
+        true
+    }
+
+    //Starts the crossfade once the current song is within the fade length of its end.
+    fn update_crossfade(&mut self){
+        self.fading_sinks.retain(|sink| !sink.empty());
+        if !self.playing || self.crossfade_secs <= 0.0 {
+            return;
+        }
+        let Some(current) = self.queue_indices.get(self.queue_current_position).cloned() else {
+            return;
+        };
+        let Some(next) = self.next_position(false).map(|p| &self.queue_indices[p]) else {
+            return;
+        };
+        if !self.crossfades_into(next) || self.crossfaded_from == Some(current.uid) || self.current_song != Some(current.song) {
+            return;
+        }
+        let Some(duration) = self.song_info.get(current.song).map(|s| s.duration) else {
+            return;
+        };
+        //short tracks fade for at most half their length, or they'd be fading the whole time
+        let fade_length = Duration::from_secs_f32(self.crossfade_secs).min(duration / 2);
+        let remaining = duration.saturating_sub(self.audio_sink.get_pos());
+        if remaining <= fade_length {
+            self.crossfaded_from = Some(current.uid);
+            self.crossfade_to_next(remaining, false);
+        }
+    }
+
+    //⏭, fades over quickly when crossfade is on, otherwise cuts straight to the next entry.
+    //Past the last entry the queue ends, unless it's repeating.
+    fn skip_forward(&mut self){
+        let Some(position) = self.next_position(true) else {
+            self.clear_sink();
+            self.queue_current_position = self.queue_indices.len();
+            self.current_song = None;
+            self.playing = false;
+            self.progress = 0.0;
+            return;
+        };
+        if self.crossfade_secs > 0.0 && self.playing {
+            let fade_length = Duration::from_secs_f32(self.crossfade_secs.min(SKIP_FADE_SECS));
+            if self.crossfade_to_next(fade_length, true) {
+                return;
+            }
+        }
+        self.queue_current_position = position;
+        self.progress = 0.0;
+        self.play_immediately(self.queue_indices[position].song);
+    }
+
+    //Keeps the entry after the current one appended to the sink, so the sink moves on to it by itself
+    //at the exact end of the current song. Once it has, the queue position is caught up here.
+    fn update_preload(&mut self){
+        let sources = self.audio_sink.len();
+        if sources <= 1 {
+            //anything cancelled ended the moment it was reached
+            self.cancelled_preloads = 0;
+            if let Some(preload) = self.preloaded.take_if(|p| p.appended) {
+                self.current_fade = preload.fade;
+                if let Some(position) = self.queue_indices.iter().position(|e| e.uid == preload.uid) {
+                    self.queue_current_position = position;
+                    self.current_song = Some(self.queue_indices[position].song);
+                    self.progress = 0.0;
+                }
+            }
+        }
+
+        let next = self.next_position(false).map(|p| self.queue_indices[p].clone());
+        if let Some(preload) = &self.preloaded {
+            if next.as_ref().is_some_and(|e| e.uid == preload.uid) {
+                return;
+            }
+            //the queue was reordered or edited, whatever is in the sink isn't next anymore
+            if preload.appended {
+                preload.fade.stop();
+                self.cancelled_preloads += 1;
+            }
+            self.preloaded = None;
+        }
+
+        let Some(next) = next else {
+            return;
+        };
+        //crossfades open the next song themselves when the time comes
+        if self.crossfades_into(&next) {
+            return;
+        }
+        if self.current_song.is_none() || sources != 1 + self.cancelled_preloads {
+            return;
+        }
+        let fade = fade::FadeHandle::default();
+        let appended = match self.open_song(next.song, next.from_album) {
+            Ok(source) => {
+                self.audio_sink.append(fade::Fadeable::new(source, fade.clone()));
+                true
+            }
+            Err(_) => false,
+        };
+        self.preloaded = Some(Preload { uid: next.uid, appended, fade });
+    }
+
+    fn export_scan_errors(&self){
+        let Some(path) = rfd::FileDialog::new().set_file_name("coral_scan_errors.txt").save_file() else {
+            return;
+        };
+        let report = self.scan_errors.iter()
+            .map(|e| format!("{}\t{}\t{}", e.kind.label(), e.path.to_string_lossy(), e.reason))
+            .collect::<Vec<_>>()
+            .join("\n");
+        if let Err(e) = std::fs::write(&path, report) {
+            println!("Failed to export scan errors: {e}");
+        }
+    }
+
+    //Library songs for the entries of a playlist, by path first and by the #EXTINF title when the path isn't
+    //known, e.g. for a playlist made on another machine. Returns the songs and how many entries weren't found.
+    fn resolve_m3u(&self, entries: &[m3u::M3uEntry]) -> (Vec<SongId>, usize){
+        let mut by_title: HashMap<String, Vec<SongId>> = HashMap::default();
+        for (id, song) in self.song_info.iter() {
+            by_title.entry(format!("{} - {}", song.artist, song.track).to_lowercase()).or_default().push(id);
+        }
+        let close_enough = |id: SongId, duration: Option<Duration>| match (duration, self.song_info.get(id)) {
+            (Some(duration), Some(song)) => song.duration.as_secs().abs_diff(duration.as_secs()) <= 2,
+            _ => true,
+        };
+        let mut songs = Vec::new();
+        let mut missing = 0;
+        for entry in entries {
+            let id = self.song_info.id_for_path(&entry.path)
+                .or_else(|| std::fs::canonicalize(&entry.path).ok().and_then(|p| self.song_info.id_for_path(&p)))
+                .or_else(|| {
+                    let candidates = by_title.get(&entry.title.as_ref()?.to_lowercase())?;
+                    candidates.iter().copied().find(|id| close_enough(*id, entry.duration))
+                });
+            match id {
+                Some(id) => songs.push(id),
+                None => missing += 1,
+            }
+        }
+        (songs, missing)
+    }
+
+    //Asks for an .m3u/.m3u8 file and returns the library songs it lists. Entries that couldn't be found are
+    //reported in a window.
+    fn import_m3u(&mut self) -> Option<(PathBuf, Vec<SongId>)>{
+        let path = rfd::FileDialog::new().add_filter("Playlist", &["m3u", "m3u8"]).pick_file()?;
+        let entries = match m3u::read(&path) {
+            Ok(entries) => entries,
+            Err(e) => {
+                self.import_report = Some(format!("Failed to read {}: {e}", path.to_string_lossy()));
+                return None;
+            }
+        };
+        let (songs, missing) = self.resolve_m3u(&entries);
+        if missing > 0 {
+            self.import_report = Some(format!("{} of {} entries in {} aren't in the library and were skipped.",
+                missing, entries.len(), path.to_string_lossy()));
+        }
+        Some((path, songs))
+    }
+
+    fn import_m3u_to_queue(&mut self){
+        if let Some((_, songs)) = self.import_m3u() {
+            for id in songs {
+                self.add_song_to_queue(id);
+            }
+        }
+    }
+
+    //Imports into a new playlist named after the file.
+    fn import_m3u_to_playlist(&mut self){
+        if let Some((path, songs)) = self.import_m3u() {
+            let name = path.file_stem().map(|n| n.to_string_lossy().to_string()).unwrap_or("Imported".to_string());
+            let id = self.playlists.create(&name);
+            self.add_to_playlist(id, &songs);
+            self.open_playlist = Some(id);
+        }
+    }
+
+    fn export_m3u(&self, songs: &[SongId], file_name: &str){
+        let Some(path) = rfd::FileDialog::new().add_filter("Playlist", &["m3u8"]).set_file_name(format!("{file_name}.m3u8")).save_file() else {
+            return;
+        };
+        let tracks: Vec<m3u::M3uTrack> = songs.iter()
+            .filter_map(|id| self.song_info.get(*id))
+            .map(|song| m3u::M3uTrack {
+                path: &song.path,
+                title: format!("{} - {}", song.artist, song.track),
+                duration: song.duration,
+            })
+            .collect();
+        if let Err(e) = m3u::write(&path, &tracks, self.m3u_relative) {
+            println!("Failed to export playlist: {e}");
+        }
+    }
+
+    //Songs of a playlist that are in the library, ones that aren't are skipped.
+    fn playlist_songs(&self, id: u64) -> Vec<SongId>{
+        match self.playlists.get(id) {
+            Some(playlist) => playlist.songs.iter().filter_map(|e| self.song_info.id_for_path(&e.path)).collect(),
+            None => self.smart_results.get(&id).cloned().unwrap_or_default(),
         }
     }
 
-    fn select_folder_and_scan(&mut self){
+    fn evaluate_smart_playlists(&mut self){
+        let context = RuleContext { play_counts: &self.play_counts, now: std::time::SystemTime::now() };
+        self.smart_results = self.playlists.smart.iter()
+            .map(|playlist| (playlist.id, playlist.evaluate(&self.song_info, &context)))
+            .collect();
+        self.smart_dirty = false;
+    }
+
+    //Replaces the queue with a playlist and plays it from entry `from` on.
+    fn play_playlist(&mut self, id: u64, from: usize){
+        let start = match self.playlists.get(id) {
+            //missing songs before the one clicked don't make it into the queue
+            Some(playlist) => playlist.songs.iter().take(from).filter(|e| self.song_info.id_for_path(&e.path).is_some()).count(),
+            //smart playlists only list songs that are in the library
+            None => from,
+        };
+        let songs = self.playlist_songs(id);
+        if songs.is_empty() {
+            return;
+        }
+        self.clear_queue();
+        for song in songs {
+            self.add_song_to_queue(song);
+        }
+        self.queue_current_position = start.min(self.queue_indices.len() - 1);
+        self.play_immediately(self.queue_indices[self.queue_current_position].song);
+    }
+
+    fn add_to_playlist(&mut self, id: u64, songs: &[SongId]){
+        let paths: Vec<PathBuf> = songs.iter().filter_map(|id| self.song_info.get(*id)).map(|s| s.path.clone()).collect();
+        self.playlists.add(id, &paths);
+    }
+
+    fn apply_playlist_pick(&mut self, pick: PlaylistPick, songs: &[SongId]){
+        let id = match pick {
+            PlaylistPick::Existing(id) => id,
+            PlaylistPick::New => {
+                let id = self.playlists.create("New Playlist");
+                //straight into renaming, nobody wants a sidebar full of "New Playlist 7"
+                self.show_playlists = true;
+                self.open_playlist = Some(id);
+                self.renaming_playlist = self.playlists.get(id).map(|p| (id, p.name.clone()));
+                id
+            }
+        };
+        self.add_to_playlist(id, songs);
+    }
+
+    //Songs of an open smart playlist in the sidebar. They're whatever the rules match, so there's no
+    //reordering or removing here.
+    fn smart_playlist_ui(&mut self, ui: &mut egui::Ui, id: u64){
+        let songs = self.playlist_songs(id);
+        ui.horizontal(|ui| {
+            if ui.button("Play").clicked() {
+                self.play_playlist(id, 0);
+            }
+            if ui.button("Append to Queue").clicked() {
+                for song in &songs {
+                    self.add_song_to_queue(*song);
+                }
+            }
+            if ui.button("Edit Rules...").clicked() {
+                self.editing_smart = self.playlists.get_smart(id)
+                    .map(|p| SmartEditor { playlist: p.clone(), is_new: false, matches: None });
+            }
+        });
+        ui.weak(smart::describe(&songs, &self.song_info));
+        let mut play_from: Option<usize> = None;
+        let mut queue: Option<SongId> = None;
+        egui::ScrollArea::vertical().id_salt("SmartPlaylistSongs").auto_shrink([false, false]).show(ui, |ui| {
+            if songs.is_empty() {
+                ui.weak("No songs match the rules.");
+            }
+            for (i, song_id) in songs.iter().enumerate() {
+                let Some(song) = self.song_info.get(*song_id) else {
+                    continue;
+                };
+                let res = ui.add(egui::Button::selectable(Some(*song_id) == self.current_song, format!("{} - {}", song.track, song.artist)).truncate())
+                    .on_hover_ui(|ui| { ui.label(song.details()); });
+                if res.double_clicked() {
+                    play_from = Some(i);
+                }
+                res.context_menu(|ui| {
+                    if ui.button("Play from Here").clicked() {
+                        play_from = Some(i);
+                        ui.close();
+                    }
+                    if ui.button("Queue Song").clicked() {
+                        queue = Some(*song_id);
+                        ui.close();
+                    }
+                });
+            }
+        });
+        if let Some(index) = play_from {
+            self.play_playlist(id, index);
+        }
+        if let Some(song) = queue {
+            self.add_song_to_queue(song);
+        }
+    }
+
+    //"Add to Playlist" submenu for context menus.
+    fn playlist_menu(ui: &mut egui::Ui, playlists: &Playlists, label: &str) -> Option<PlaylistPick>{
+        let mut pick = None;
+        ui.menu_button(label, |ui| {
+            if ui.button("New Playlist").clicked() {
+                pick = Some(PlaylistPick::New);
+            }
+            if !playlists.lists.is_empty() {
+                ui.separator();
+            }
+            for playlist in &playlists.lists {
+                if ui.button(&playlist.name).clicked() {
+                    pick = Some(PlaylistPick::Existing(playlist.id));
+                }
+            }
+        });
+        pick
+    }
+
+    fn select_folder_and_scan(&mut self, ctx: &egui::Context){
         if let Some(folder) = rfd::FileDialog::new().pick_folder() {
             println!("Selected folder: {:?}", folder);
             if let Some(_) = self.dirs.iter().position(|s| *s == folder){
                 println!("Directory already exists.");
             }else{
                 self.dirs.push(folder.clone());
-                self.scan_folder(folder);
+                if let Some(watcher) = self.watcher.as_mut(){
+                    watcher.watch(&folder);
+                }
+                self.scan_folders(vec![folder], ctx);
             }
         }
     }
 
-    fn scan_folder(&mut self, path: PathBuf){
-        if let Ok(entries) = std::fs::read_dir(&path) {
-            for entry in entries.flatten() {
-                let mut new_song = SongInfo::default();
-                
-                let path = entry.path();
-                let tagged_file = match Probe::open(path.clone()).unwrap().guess_file_type().unwrap().read()
-                {
-                    Ok(file) => file,
-                    Err(_) => {
-                        continue;
+    //Walks every directory again, e.g. after the scan options changed. Songs are matched up by path, so they keep
+    //their ids and the queue stays as it is. Whatever the new scan doesn't find anymore is removed once it's done.
+    fn rescan_library(&mut self, ctx: &egui::Context){
+        //dropping the old job disconnects its worker, which stops at the next file
+        self.scan_job = None;
+        self.pending_scan_roots.clear();
+        self.rescan_found = Some(HashSet::new());
+        if let Some(covers) = self.covers.as_mut(){
+            covers.clear();
+        }
+        self.scan_folders(self.dirs.clone(), ctx);
+    }
+
+    //Regroups the albums after songs were added, changed or removed. During a scan this is throttled,
+    //the final rebuild happens when the scan finishes.
+    fn rebuild_albums(&mut self){
+        self.library = LibraryInfo::rebuild(&self.song_info);
+        let albums = &self.library.albums;
+        self.enabled_album.retain(|key, _| albums.contains_key(key));
+        for key in self.library.albums.keys(){
+            self.enabled_album.entry(key.clone()).or_insert(false);
+        }
+        self.albums_dirty = false;
+        self.albums_rebuilt_at = Instant::now();
+        self.smart_dirty = true;
+    }
+
+    //Starts a background scan of the given roots, or queues them behind the scan already running.
+    fn scan_folders(&mut self, roots: Vec<PathBuf>, ctx: &egui::Context){
+        if self.scan_job.is_some(){
+            self.pending_scan_roots.extend(roots);
+            return;
+        }
+        self.scan_errors.retain(|e| !roots.iter().any(|r| e.path.starts_with(r)));
+        self.scan_job = Some(scan::ScanJob::start(roots, self.dirs.clone(), self.scan_options.clone(), cache::cache_path(), ctx.clone()));
+    }
+
+    fn poll_scan(&mut self, ctx: &egui::Context){
+        let Some(job) = self.scan_job.as_mut() else {
+            return;
+        };
+        let cancelled = job.is_cancelled();
+        let mut finished = false;
+        for message in job.poll(){
+            match message {
+                //a directory can be removed while its scan is still running, drop whatever arrives for it afterwards
+                scan::ScanMessage::Song(song) if self.dirs.iter().any(|d| song.path.starts_with(d)) => {
+                    if let Some(found) = self.rescan_found.as_mut() {
+                        found.insert(song.path.clone());
                     }
-                };
-                if let Some(prim_tag) = tagged_file.primary_tag() {
-                    new_song.artist = prim_tag.get_string(&tag::ItemKey::TrackArtist).unwrap_or("Unknown Artist").to_string();
-                    new_song.track = prim_tag.get_string(&tag::ItemKey::TrackTitle).unwrap_or("Unknown Title").to_string();
-                    new_song.album = prim_tag.get_string(&tag::ItemKey::AlbumTitle).unwrap_or("Unknown Album").to_string();
-                    new_song.track_number = Some(prim_tag.get_string(&tag::ItemKey::TrackNumber).unwrap().parse::<usize>().unwrap_or(usize::MAX));
-                } else {
-                    let file_name = path.file_stem().and_then(|os| os.to_str()).unwrap_or("Unknown - Unknown").to_string();
-                    let artist_title = file_name.split_at_checked(file_name.find("-").expect("rename file to have Artist - Title"));
-                    if artist_title.is_some(){
-                        new_song.artist = artist_title.unwrap().0.to_string();
-                        new_song.track = artist_title.unwrap().1.to_string();
-                        new_song.album = "".to_string();
-                    }
-                }
-                new_song.path = path.clone();
-                let file = File::open(new_song.path.clone());
-                if file.is_ok() {
-                    let file_buffer = BufReader::new(file.unwrap());  
-                    let source = Decoder::try_from(file_buffer).unwrap();
-                    new_song.duration = source.total_duration().unwrap_or(std::time::Duration::from_secs(0));
-                }
-                let album_artist = new_song.artist.clone().split(|c: char| c == ',' || c == '&' || c == '/').map(|s| s.trim()).find(|s| !s.is_empty()).map(|s| s.to_string());
-                let album_key = (new_song.album.clone(), album_artist.unwrap_or(new_song.artist.clone()));
-                
-                self.enabled_album.insert(album_key.clone(), false);
-                self.song_info.push(new_song.clone());
-                let song_index = self.song_info.len()-1;
-                self.library.albums
-                    .entry(album_key.clone())
-                    .or_insert(AlbumInfo {
-                        songs: Vec::new(),
-                    })
-                    .songs
-                    .push(song_index);
+                    self.add_song(*song);
+                }
+                scan::ScanMessage::Error(error) if self.dirs.iter().any(|d| error.path.starts_with(d)) => self.scan_errors.push(error),
+                scan::ScanMessage::Finished => finished = true,
+                _ => {}
+            }
+        }
+        if finished {
+            self.scan_job = None;
+            //a cancelled rescan didn't get to look at everything, nothing is removed then
+            if let Some(found) = self.rescan_found.take().filter(|_| !cancelled) {
+                self.remove_songs(|song| !found.contains(&song.path));
+            }
+            self.rebuild_albums();
+            //a cancelled scan only saw part of the library, keep the previous cache instead
+            if !cancelled && self.pending_scan_roots.is_empty() {
+                self.save_library_cache();
+            }
+            if !self.pending_scan_roots.is_empty(){
+                let roots = std::mem::take(&mut self.pending_scan_roots);
+                self.scan_folders(roots, ctx);
+            }else{
+                self.restore_queue();
             }
         }
     }
-    
-    fn play_immediately_with_index(&mut self, index: usize){
-        self.audio_sink.clear();
-        self.song_current_position = Some(index);
-        if let Some(song) = self.song_info.get(index){
-            self.queue_song_from_file(song.path.clone());
+
+    fn saved_queue(&self) -> SavedQueue{
+        let mut saved = SavedQueue {
+            position: self.queue_current_position,
+            seek_secs: if self.current_song.is_some() { self.audio_sink.get_pos().as_secs_f32() } else { 0.0 },
+            shuffled: self.shuffled,
+            ..Default::default()
+        };
+        let mut saved_index: HashMap<usize, usize> = HashMap::default();
+        for (i, entry) in self.queue_indices.iter().enumerate() {
+            match self.song_info.get(entry.song) {
+                Some(song) => {
+                    saved_index.insert(entry.uid, saved.entries.len());
+                    saved.entries.push((song.path.clone(), entry.from_album));
+                }
+                None if i < self.queue_current_position => saved.position -= 1,
+                None => {}
+            }
+        }
+        if self.shuffled {
+            saved.unshuffled = self.unshuffled.iter().filter_map(|uid| saved_index.get(uid).copied()).collect();
+        }
+        saved
+    }
+
+    //Puts last session's queue back, paused at the spot it was left at. Songs that have gone missing since are
+    //dropped, and if that was the current one its successor is cued up from the start instead.
+    fn restore_queue(&mut self){
+        let Some(saved) = self.pending_restore.take() else {
+            return;
+        };
+        //something was queued while the scan ran, that wins
+        if !self.queue_indices.is_empty(){
+            return;
+        }
+        let mut position = saved.position;
+        let mut seek_secs = saved.seek_secs;
+        let mut restored_uids: HashMap<usize, usize> = HashMap::default();
+        for (i, (path, from_album)) in saved.entries.into_iter().enumerate() {
+            match self.song_info.id_for_path(&path) {
+                Some(id) => {
+                    let e = self.new_queue_entry(id, from_album);
+                    restored_uids.insert(i, e.uid);
+                    self.queue_indices.push(e);
+                }
+                None if i < saved.position => position -= 1,
+                None => {
+                    if i == saved.position {
+                        seek_secs = 0.0;
+                    }
+                }
+            }
+        }
+        self.shuffled = saved.shuffled;
+        self.unshuffled = saved.unshuffled.iter().filter_map(|i| restored_uids.get(i).copied()).collect();
+        self.queue_current_position = position.min(self.queue_indices.len());
+        if self.queue_current_position >= self.queue_indices.len() {
+            return;
+        }
+        //the song is loaded but waits for ▶, and if it doesn't open anymore nothing starts by itself either
+        self.cue_current(false);
+        let Some(song) = self.current_song.filter(|_| !self.open_failed) else {
+            return;
+        };
+        if seek_secs > 0.0 {
+            self.seek_to(seek_secs);
+            if let Some(song) = self.song_info.get(song) {
+                self.progress = seek_secs / song.duration.as_secs_f32();
+            }
+        }
+    }
+
+    //Songs the loudness analysis still has to measure. An album is measured as a whole as soon as one of its
+    //songs has no ReplayGain, so it gets an album gain too. Songs measured before are left out, which is what
+    //lets a cancelled or interrupted analysis carry on where it stopped.
+    fn songs_needing_analysis(&self) -> Vec<(SongId, PathBuf)>{
+        let mut songs = Vec::new();
+        for (_, album) in self.sorted_albums(){
+            let needs_gain = album.songs.iter().filter_map(|id| self.song_info.get(*id)).any(|s| s.gain().is_empty());
+            if !needs_gain {
+                continue;
+            }
+            for id in album.songs {
+                if self.analysis_failed.contains(&id) {
+                    continue;
+                }
+                if let Some(song) = self.song_info.get(id).filter(|s| s.loudness.is_none()) {
+                    songs.push((id, song.path.clone()));
+                }
+            }
+        }
+        songs
+    }
+
+    fn start_analysis(&mut self, ctx: &egui::Context){
+        let songs = self.songs_needing_analysis();
+        if !songs.is_empty(){
+            self.analysis_job = Some(analysis::AnalysisJob::start(songs, ctx.clone()));
+        }
+    }
+
+    fn poll_analysis(&mut self){
+        let Some(job) = self.analysis_job.as_mut() else {
+            return;
+        };
+        let mut finished = false;
+        let mut measured = Vec::new();
+        for message in job.poll(){
+            match message {
+                analysis::AnalysisMessage::Measured(id, loudness) => {
+                    if let Some(song) = self.song_info.get_mut(id) {
+                        song.loudness = Some(loudness);
+                        measured.push(id);
+                    }
+                }
+                analysis::AnalysisMessage::Failed(id, e) => {
+                    if let Some(song) = self.song_info.get(id) {
+                        println!("Failed to analyze {:?}: {e}", song.path);
+                    }
+                    self.analysis_failed.insert(id);
+                    measured.push(id);
+                }
+                analysis::AnalysisMessage::Finished => finished = true,
+                analysis::AnalysisMessage::Progress { .. } => {}
+            }
+        }
+        let mut albums_completed = false;
+        for id in measured {
+            albums_completed |= self.complete_album_loudness(id);
+        }
+        if finished {
+            self.analysis_job = None;
+        }
+        //saved as albums complete so closing the app mid-analysis keeps them
+        if (finished || albums_completed) && self.scan_job.is_none() {
+            self.save_library_cache();
+        }
+    }
+
+    //Once every song in the album of `id` is measured, pools them into the album loudness and peak and hands
+    //those to each song. Returns whether that happened.
+    fn complete_album_loudness(&mut self, id: SongId) -> bool{
+        let Some(songs) = self.library.song_album.get(&id).and_then(|key| self.library.albums.get(key)).map(|a| a.songs.clone()) else {
+            return false;
+        };
+        let measured: Option<Vec<analysis::Loudness>> = songs.iter()
+            .filter(|id| !self.analysis_failed.contains(id))
+            .map(|id| self.song_info.get(*id).and_then(|s| s.loudness))
+            .collect();
+        let Some(measured) = measured.filter(|m| !m.is_empty()) else {
+            return false;
+        };
+        let (integrated, peak) = analysis::album_loudness(&measured);
+
+        let mut writes = Vec::new();
+        for id in songs {
+            let Some(song) = self.song_info.get_mut(id) else {
+                continue;
+            };
+            if let Some(loudness) = song.loudness.as_mut() {
+                loudness.album_integrated = integrated;
+                loudness.album_peak = Some(peak);
+                //songs that already had ReplayGain tags keep them as they are. Untagged songs aren't written either,
+                //a tag holding nothing but ReplayGain would replace the names guessed from their path on the next scan
+                if self.analysis_writeback && song.replay_gain.is_empty() && !song.tags_inferred {
+                    writes.push((song.path.clone(), loudness.replay_gain()));
+                }
+            }
+        }
+        if !writes.is_empty(){
+            //the watcher picks the rewritten files up and rescans them
+            std::thread::spawn(move || {
+                for (path, gain) in writes {
+                    if let Err(e) = replaygain::write_tags(&path, &gain) {
+                        println!("Failed to write ReplayGain to {:?}: {e}", path);
+                    }
+                }
+            });
+        }
+        true
+    }
+
+    fn save_library_cache(&self){
+        if let Some(path) = cache::cache_path(){
+            let songs: Vec<SongInfo> = self.song_info.iter().map(|(_, song)| song.clone()).collect();
+            cache::save_in_background(path, songs);
+        }
+    }
+
+    //Albums in the order picked in the library header, ties fall back to album then artist name.
+    fn sorted_albums(&self) -> Vec<(AlbumKey, AlbumInfo)>{
+        let mut albums: Vec<(AlbumKey, AlbumInfo)> = self.library.albums.clone().into_iter().collect();
+        let first_song = |album: &AlbumInfo| album.songs.first().and_then(|id| self.song_info.get(*id));
+        match self.library_sort {
+            LibrarySort::Album => albums.sort_by(|a, b| a.0.0.to_lowercase().cmp(&b.0.0.to_lowercase()).then(a.0.1.cmp(&b.0.1))),
+            LibrarySort::Artist => albums.sort_by(|a, b| a.0.1.to_lowercase().cmp(&b.0.1.to_lowercase()).then(a.0.0.cmp(&b.0.0))),
+            LibrarySort::Year => albums.sort_by(|a, b| {
+                let year = |album: &AlbumInfo| first_song(album).and_then(|s| s.year).unwrap_or(u32::MAX);
+                year(&a.1).cmp(&year(&b.1)).then(a.0.cmp(&b.0))
+            }),
+            LibrarySort::Genre => albums.sort_by(|a, b| {
+                let genre = |album: &AlbumInfo| first_song(album).and_then(|s| s.genre.clone()).unwrap_or_default().to_lowercase();
+                genre(&a.1).cmp(&genre(&b.1)).then(a.0.cmp(&b.0))
+            }),
+        }
+        albums
+    }
+
+    fn add_song(&mut self, new_song: SongInfo){
+        //a song that's already known keeps its id, the file was rewritten or retagged
+        self.song_info.insert(new_song);
+        self.albums_dirty = true;
+    }
+
+    //Removes every song matching `remove` along with its album entry and any queue entries pointing at it.
+    //If the playing song goes away the queue carries on with whatever follows it.
+    fn remove_songs(&mut self, remove: impl Fn(&SongInfo) -> bool){
+        let removed: Vec<SongId> = self.song_info.iter().filter(|(_, song)| remove(song)).map(|(id, _)| id).collect();
+        if removed.is_empty(){
+            return;
+        }
+        for id in removed {
+            self.song_info.remove(id);
+        }
+        self.rebuild_albums();
+
+        let song_info = &self.song_info;
+        let removed_before_current = self.queue_indices.iter()
+            .take(self.queue_current_position)
+            .filter(|e| !song_info.contains(e.song))
+            .count();
+        self.queue_indices.retain(|e| song_info.contains(e.song));
+        self.queue_current_position -= removed_before_current;
+
+        if self.current_song.is_some_and(|id| !self.song_info.contains(id)) {
+            //queue_current_position already points at the following entry, it plays on if the removed song was
+            //playing and waits paused if it was paused
+            self.cue_current(self.playing);
+        }
+    }
+
+    //Loads the entry at the queue position into an emptied sink, playing or paused. If it can't be opened while
+    //paused, the sink stays empty until ▶ moves on to the entry after it. Past the end of the queue nothing is loaded.
+    fn cue_current(&mut self, play: bool){
+        self.clear_sink();
+        self.progress = 0.0;
+        let Some(entry) = self.queue_indices.get(self.queue_current_position).cloned() else {
+            self.current_song = None;
+            self.playing = false;
+            return;
+        };
+        self.current_song = Some(entry.song);
+        self.queue_song(entry.song, entry.from_album);
+        self.playing = play;
+        if play {
+            self.audio_sink.play();
+        }
+        self.waiting_for_play = !play && self.open_failed;
+    }
+
+    //Forgets a library root along with every song that was found below it, unless another root still covers the song.
+    fn remove_directory(&mut self, dir_index: usize){
+        let dir = self.dirs.remove(dir_index);
+        if let Some(watcher) = self.watcher.as_mut(){
+            watcher.unwatch(&dir);
+        }
+        self.pending_scan_roots.retain(|p| !p.starts_with(&dir));
+
+        self.scan_errors.retain(|e| !e.path.starts_with(&dir));
+        let remaining = self.dirs.clone();
+        self.remove_songs(|song| song.path.starts_with(&dir) && !remaining.iter().any(|d| song.path.starts_with(d)));
+        if self.scan_job.is_none(){
+            self.save_library_cache();
+        }
+    }
+
+    fn poll_watcher(&mut self, ctx: &egui::Context){
+        let Some(watcher) = self.watcher.as_mut() else {
+            return;
+        };
+        let changed = watcher.poll();
+        if changed.is_empty(){
+            return;
+        }
+
+        let (existing, missing): (Vec<PathBuf>, Vec<PathBuf>) = changed.into_iter().partition(|p| p.exists());
+        if !missing.is_empty(){
+            //deleted or renamed away, either a single file or a whole folder
+            self.remove_songs(|song| missing.iter().any(|p| song.path.starts_with(p)));
+            self.scan_errors.retain(|e| !missing.iter().any(|p| e.path.starts_with(p)));
+            if self.scan_job.is_none(){
+                self.save_library_cache();
+            }
+        }
+
+        //the scan leaves out whatever is hidden, ignored or too deep below its library root
+        if !existing.is_empty(){
+            self.scan_folders(existing, ctx);
+        }
+    }
+
+    fn play_immediately(&mut self, id: SongId){
+        self.clear_sink();
+        self.current_song = Some(id);
+        if self.song_info.contains(id){
+            let from_album = self.queue_indices.get(self.queue_current_position).is_some_and(|e| e.song == id && e.from_album);
+            self.queue_song(id, from_album);
             self.audio_sink.play();
             self.playing = true;
         }
@@ -207,25 +1152,30 @@ impl PlayerApp {
         self.progress = 0.0;
 
         if self.playing{
-            self.queue_current_position += 1;
+            self.queue_current_position = self.next_position(self.open_failed).unwrap_or(self.queue_indices.len());
         }
 
         if self.queue_current_position < self.queue_indices.len(){
             self.playing = true;
-            self.song_current_position = Some(self.queue_indices[self.queue_current_position].song_index);
-            self.play_immediately_with_index(self.song_current_position.unwrap());
+            self.play_immediately(self.queue_indices[self.queue_current_position].song);
         }else{
             self.playing = false;
-            self.song_current_position = None;
+            self.current_song = None;
         }
     }
 
-    fn add_song_to_queue_with_index(&mut self, index: usize){
+    fn new_queue_entry(&mut self, id: SongId, from_album: bool) -> QueueEntry{
         let e = QueueEntry{
-            song_index: index,
+            song: id,
             uid: self.queue_next_uid,
+            from_album,
         };
         self.queue_next_uid += 1;
+        e
+    }
+
+    fn add_song_to_queue(&mut self, id: SongId){
+        let e = self.new_queue_entry(id, false);
         self.queue_indices.push(e);
     }
 
@@ -234,14 +1184,19 @@ impl PlayerApp {
             self.playing = false;
             return;
         }
+        self.waiting_for_play = false;
         if self.queue_current_position >= self.queue_indices.len() {
             self.queue_current_position = 0;            
-            self.song_current_position = Some(self.queue_indices[self.queue_current_position].song_index);
-            self.play_immediately_with_index(self.song_current_position.unwrap());
+            self.play_immediately(self.queue_indices[self.queue_current_position].song);
         }
         self.playing = true;
         self.audio_sink.play();
-        let song = self.song_info[self.song_current_position.unwrap()].clone();
+        for sink in &self.fading_sinks {
+            sink.play();
+        }
+        let Some(song) = self.current_song.and_then(|id| self.song_info.get(id)).cloned() else {
+            return;
+        };
         self.discord_client.set_activity(activity::Activity::new()
                         .activity_type(activity::ActivityType::Listening)
                         .state(&song.artist.clone())
@@ -260,6 +1215,9 @@ impl PlayerApp {
     fn pause(&mut self){    
         self.playing = false;
         self.audio_sink.pause();
+        for sink in &self.fading_sinks {
+            sink.pause();
+        }
         self.discord_client.set_activity(activity::Activity::new()
                     .activity_type(activity::ActivityType::Listening)
                     .state("Paused...")
@@ -272,14 +1230,46 @@ impl PlayerApp {
                 println!("Updated paused discord activity");
     }
 
+    //Plays a song right away, inserted after the current queue entry so the queue carries on from there.
+    fn play_now(&mut self, id: SongId){
+        let position = self.next_insert_position();
+        self.insert_into_queue(position, &[id], false);
+        self.queue_current_position = position;
+        self.play_immediately(id);
+    }
+
     fn back(&mut self){
+        //close to the start, go back to whatever was heard before this
+        if self.progress < 0.1 {
+            while let Some(entry) = self.history.pop() {
+                if !self.song_info.contains(entry.song) {
+                    continue;
+                }
+                //back into its queue entry if that's still around (uids restart when the queue is cleared), otherwise
+                //it's put in front of the current one
+                let position = match entry.queue_uid.and_then(|uid| self.queue_indices.iter().position(|e| e.uid == uid && e.song == entry.song)) {
+                    Some(position) => position,
+                    None => {
+                        let e = self.new_queue_entry(entry.song, false);
+                        let position = self.queue_current_position.min(self.queue_indices.len());
+                        self.queue_indices.insert(position, e);
+                        position
+                    }
+                };
+                self.leaving_via_back = true;
+                self.queue_current_position = position;
+                self.play_immediately(entry.song);
+                return;
+            }
+        }
         //if time close enough to start ,go back in queue, otherwise seek to 0
-        if self.progress < 0.1 && self.queue_current_position > 0 {
-            //go back in queue
-            self.audio_sink.clear();
-            self.queue_current_position -= 1;
-            let song_index = self.queue_indices[self.queue_current_position].song_index;
-            self.play_immediately_with_index(song_index);
+        let wraps = self.repeat == RepeatMode::Queue && !self.queue_indices.is_empty();
+        if self.progress < 0.1 && (self.queue_current_position > 0 || wraps) {
+            //go back in queue, from the top to the bottom when the queue repeats
+            self.clear_sink();
+            self.queue_current_position = self.queue_current_position.checked_sub(1).unwrap_or(self.queue_indices.len() - 1);
+            let id = self.queue_indices[self.queue_current_position].song;
+            self.play_immediately(id);
         }else{
             self.seek_to(0.0);
         }
@@ -295,6 +1285,22 @@ impl PlayerApp {
         }
     }
 
+    //Cover of the album the song was grouped into, starts loading it if it isn't cached yet.
+    fn song_cover(&mut self, id: SongId) -> Option<egui::TextureHandle>{
+        let album = self.library.song_album.get(&id)?;
+        let song = self.song_info.get(id)?;
+        self.covers.as_mut()?.get(album, &song.path)
+    }
+
+    //Draws a cover centered in `rect`, keeping its aspect ratio.
+    fn paint_cover(ui: &egui::Ui, texture: &egui::TextureHandle, rect: egui::Rect){
+        let size = texture.size_vec2();
+        let scale = (rect.width() / size.x).min(rect.height() / size.y);
+        let cover_rect = egui::Rect::from_center_size(rect.center(), size * scale);
+        let uv = egui::Rect::from_min_max(egui::pos2(0.0, 0.0), egui::pos2(1.0, 1.0));
+        ui.painter().image(texture.id(), cover_rect, uv, Color32::WHITE);
+    }
+
     fn seek_to(&mut self, seconds: f32){
         let res = self.audio_sink.try_seek(std::time::Duration::from_secs_f32(seconds));
         if res.is_ok(){
@@ -306,54 +1312,174 @@ impl PlayerApp {
         }
     }
 
-    fn queue_album(&mut self, song_info: SongInfo) {
-        let album_artist = song_info.artist.clone().split(|c: char| c == ',' || c == '&' || c == '/').map(|s| s.trim()).find(|s| !s.is_empty()).map(|s| s.to_string());
-        let album_key = (song_info.album.clone(), album_artist.unwrap_or(song_info.artist.clone()));
-        let mut album_songs: Option<Vec<usize>> = None;
-        if let Some(album) = self.library.albums.get_mut(&album_key) {
-            album_songs = Some(album.songs.clone());
-        }
+    fn album_songs(&self, album_key: &AlbumKey) -> Vec<SongId> {
+        self.library.albums.get(album_key).map(|a| a.songs.clone()).unwrap_or_default()
+    }
 
-        //avoiding double borrow...
-        if album_songs.is_some(){
-            let mut aos = album_songs.unwrap();
-            aos.sort_by(|a, b| {
-                self.song_info[*a].track_number.unwrap_or(usize::MAX).cmp(&self.song_info[*b].track_number.unwrap_or(usize::MAX))
-            });
-    
-            for i in 0..aos.len() {
-                self.add_song_to_queue_with_index(aos[i]);
-            }
+    fn queue_album(&mut self, album_key: &AlbumKey) {
+        let album_songs = self.album_songs(album_key);
+        self.insert_into_queue(self.queue_indices.len(), &album_songs, true);
+    }
+
+    //Where "Play Next" puts songs: right after the current entry, or where the queue picks up again when nothing's playing.
+    fn next_insert_position(&self) -> usize{
+        let after = if self.current_song.is_some() { 1 } else { 0 };
+        (self.queue_current_position + after).min(self.queue_indices.len())
+    }
+
+    //Past the rest of the album that's playing so it isn't cut in half, same as next_insert_position when
+    //the current song wasn't queued with its album.
+    fn after_album_insert_position(&self) -> usize{
+        let mut position = self.next_insert_position();
+        let Some(current) = self.queue_indices.get(self.queue_current_position).filter(|e| e.from_album && Some(e.song) == self.current_song) else {
+            return position;
+        };
+        let album = self.library.song_album.get(&current.song);
+        while self.queue_indices.get(position).is_some_and(|e| e.from_album && self.library.song_album.get(&e.song) == album) {
+            position += 1;
         }
+        position
+    }
+
+    fn insert_into_queue(&mut self, position: usize, ids: &[SongId], from_album: bool){
+        let entries: Vec<QueueEntry> = ids.iter().map(|id| self.new_queue_entry(*id, from_album)).collect();
+        self.queue_indices.splice(position..position, entries);
+    }
+
+    fn play_songs_next(&mut self, ids: &[SongId], from_album: bool){
+        self.insert_into_queue(self.next_insert_position(), ids, from_album);
+    }
+
+    fn play_songs_after_album(&mut self, ids: &[SongId], from_album: bool){
+        self.insert_into_queue(self.after_album_insert_position(), ids, from_album);
     }
 
     fn shuffle_play(&mut self){
-        self.queue_indices = (0..self.song_info.len())
-            .map(|i| {
-                let e = QueueEntry{
-                    song_index: i,
-                    uid: self.queue_next_uid
-                };
-                self.queue_next_uid += 1;
-                e
-            }).collect();
-        self.shuffle_queue();
+        //album shuffle plays whole albums, so they get album gain like albums queued by hand
+        let from_album = self.shuffle_mode == ShuffleMode::Albums;
+        self.queue_indices = self.song_info.ids().collect::<Vec<_>>().into_iter()
+            .map(|id| self.new_queue_entry(id, from_album))
+            .collect();
+        self.shuffled = false;
+        self.shuffle_all();
+    }
+
+    fn shuffled_entries(&self, entries: Vec<QueueEntry>) -> Vec<QueueEntry>{
+        let songs: Vec<SongId> = entries.iter().map(|e| e.song).collect();
+        let context = ShuffleContext { songs: &self.song_info, library: &self.library, play_counts: &self.play_counts };
+        let order = shuffle::shuffled_order(&songs, self.shuffle_mode, &context);
+        let mut entries: Vec<Option<QueueEntry>> = entries.into_iter().map(Some).collect();
+        order.into_iter().filter_map(|i| entries[i].take()).collect()
+    }
+
+    //Remembers the order from before the first shuffle, later reshuffles keep that one.
+    fn remember_unshuffled(&mut self){
+        if !self.shuffled {
+            self.unshuffled = self.queue_indices.iter().map(|e| e.uid).collect();
+            self.shuffled = true;
+        }
+    }
+
+    //Shuffles the whole queue and plays it from the top.
+    fn shuffle_all(&mut self){
+        if self.queue_indices.is_empty() {
+            return;
+        }
+        self.remember_unshuffled();
+        let entries = std::mem::take(&mut self.queue_indices);
+        self.queue_indices = self.shuffled_entries(entries);
+        self.queue_current_position = 0;
+        self.play_immediately(self.queue_indices[0].song);
     }
 
+    //Shuffles what's still to come without interrupting the current song, which moves to the front. Entries
+    //that were already played go after the shuffled ones in their old order, so nothing is lost.
     fn shuffle_queue(&mut self){
-        self.audio_sink.clear();
-        let mut rng = rng();
-        self.queue_indices.shuffle(&mut rng);
+        let has_current = self.queue_indices.get(self.queue_current_position).is_some_and(|e| Some(e.song) == self.current_song);
+        if !has_current {
+            self.shuffle_all();
+            return;
+        }
+        self.remember_unshuffled();
+        let mut played = std::mem::take(&mut self.queue_indices);
+        let upcoming = played.split_off(self.queue_current_position + 1);
+        let current = played.pop();
+        let upcoming = self.shuffled_entries(upcoming);
+        self.queue_indices = current.into_iter().chain(upcoming).chain(played).collect();
         self.queue_current_position = 0;
-        self.play_immediately_with_index(self.queue_indices[0].song_index);
+    }
+
+    //Back to the order from before shuffling, still on the same entry. Entries queued since go at the end.
+    fn unshuffle_queue(&mut self){
+        let rank: HashMap<usize, usize> = self.unshuffled.iter().enumerate().map(|(i, uid)| (*uid, i)).collect();
+        let current_uid = self.queue_indices.get(self.queue_current_position).map(|e| e.uid);
+        self.queue_indices.sort_by_key(|e| rank.get(&e.uid).copied().unwrap_or(usize::MAX));
+        if let Some(position) = current_uid.and_then(|uid| self.queue_indices.iter().position(|e| e.uid == uid)) {
+            self.queue_current_position = position;
+        }
+        self.shuffled = false;
+        self.unshuffled.clear();
+    }
+
+    //Songs of the given entries, in queue order.
+    fn queue_songs(&self, uids: &HashSet<usize>) -> Vec<SongId>{
+        self.queue_indices.iter().filter(|e| uids.contains(&e.uid)).map(|e| e.song).collect()
+    }
+
+    fn remove_queue_entries(&mut self, uids: &HashSet<usize>){
+        let removes_current = self.queue_indices.get(self.queue_current_position).is_some_and(|e| uids.contains(&e.uid));
+        let before = self.queue_indices.iter().take(self.queue_current_position).filter(|e| uids.contains(&e.uid)).count();
+        self.queue_indices.retain(|e| !uids.contains(&e.uid));
+        self.queue_current_position -= before;
+        if removes_current {
+            self.clear_sink();
+            match self.queue_indices.get(self.queue_current_position).map(|e| e.song) {
+                Some(id) => self.play_immediately(id),
+                None => {
+                    self.current_song = None;
+                    self.playing = false;
+                }
+            }
+        }
+    }
+
+    //Moves the entries to right after the current one, in the order they were in. The current entry itself stays put.
+    fn move_queue_entries_next(&mut self, uids: &HashSet<usize>){
+        let current_uid = self.queue_indices.get(self.queue_current_position).map(|e| e.uid);
+        let (moved, rest): (Vec<QueueEntry>, Vec<QueueEntry>) = std::mem::take(&mut self.queue_indices).into_iter()
+            .partition(|e| uids.contains(&e.uid) && Some(e.uid) != current_uid);
+        self.queue_indices = rest;
+        self.queue_current_position = current_uid
+            .and_then(|uid| self.queue_indices.iter().position(|e| e.uid == uid))
+            .unwrap_or(self.queue_indices.len());
+        let position = self.next_insert_position();
+        self.queue_indices.splice(position..position, moved);
+    }
+
+    //egui_dnd only moves the entry that was dragged, this brings the rest of the selection along so the whole
+    //selection ends up in one piece where it was dropped.
+    fn gather_queue_entries(&mut self, dragged: usize, selection: &[usize]){
+        let current_uid = self.queue_indices.get(self.queue_current_position).map(|e| e.uid);
+        let block: Vec<QueueEntry> = selection.iter()
+            .filter_map(|uid| self.queue_indices.iter().find(|e| e.uid == *uid).cloned())
+            .collect();
+        self.queue_indices.retain(|e| e.uid == dragged || !selection.contains(&e.uid));
+        if let Some(at) = self.queue_indices.iter().position(|e| e.uid == dragged) {
+            self.queue_indices.splice(at..=at, block);
+        }
+        if let Some(position) = current_uid.and_then(|uid| self.queue_indices.iter().position(|e| e.uid == uid)) {
+            self.queue_current_position = position;
+        }
     }
 
     fn clear_queue(&mut self){
+        self.waiting_for_play = false;
         self.queue_next_uid = 0;
         self.queue_current_position = 0;
         self.queue_indices = Vec::new();
-        self.audio_sink.clear();
-        self.song_current_position = None;
+        self.shuffled = false;
+        self.clear_sink();
+        self.current_song = None;
         self.playing = false;
     }
 }
@@ -378,22 +1504,76 @@ impl Default for PlayerApp {
         Self {
             playing: false,
             volume: 0.5,
-            song_info: Vec::new(),
-            song_current_position: None,
+            song_info: SongStore::default(),
+            current_song: None,
 
             queue_indices: Vec::new(),
             queue_next_uid: 0,
             queue_current_position: 0,
             progress: 0.0,
+            repeat: RepeatMode::default(),
+            shuffled: false,
+            unshuffled: Vec::new(),
+            pending_restore: None,
+            history: Default::default(),
+            leaving_via_back: false,
+            show_history: false,
+            library_selection: Selection::default(),
+            library_order: Vec::new(),
+            queue_selection: Selection::default(),
+            queue_dragged: None,
+            selection_focus: SelectionFocus::default(),
+            shuffle_mode: ShuffleMode::default(),
+            m3u_relative: false,
+            import_report: None,
+            playlists: Playlists::default(),
+            show_playlists: false,
+            open_playlist: None,
+            renaming_playlist: None,
+            deleting_playlist: None,
+            smart_results: HashMap::default(),
+            smart_dirty: true,
+            editing_smart: None,
+            play_counts: HashMap::default(),
+            open_failed: false,
+            waiting_for_play: false,
+            preloaded: None,
+            cancelled_preloads: 0,
+            current_fade: Default::default(),
+            fading_sinks: Vec::new(),
+            crossfade_secs: 0.0,
+            crossfade_curve: fade::FadeCurve::default(),
+            crossfade_skip_album: true,
+            crossfaded_from: None,
+            gain_mode: replaygain::GainMode::default(),
+            prevent_clipping: true,
+            analysis_job: None,
+            analysis_failed: HashSet::new(),
+            analysis_writeback: false,
+            show_playback: false,
 
             enabled_album: HashMap::default(),
             show_dirs: false,
             dirs: Vec::new(),
+            scan_options: Default::default(),
+            scan_job: None,
+            pending_scan_roots: Vec::new(),
+            rescan_found: None,
+            scan_errors: Vec::new(),
+            show_scan_errors: false,
+            show_unsupported_files: false,
+            show_tag_patterns: false,
+            pattern_test_path: "Artist/Album/01 - Title.flac".to_string(),
+            watcher: None,
+            covers: None,
             library: Default::default(),
+            albums_dirty: false,
+            albums_rebuilt_at: Instant::now(),
             filter_text: "".to_string(),
+            library_sort: LibrarySort::default(),
 
             discord_client: client,
-            _output_stream: ous,
+            output_stream: ous,
             audio_sink: aus
         }
     }
@@ -403,62 +1583,420 @@ impl Default for PlayerApp {
 impl eframe::App for PlayerApp {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
         //Cache for updates -> discord integration
-        let currently_playing: Option<usize> = self.song_current_position;
+        let currently_playing: Option<SongId> = self.current_song;
 
-        if self.audio_sink.empty() {
+        self.poll_scan(ctx);
+        self.poll_analysis();
+        self.poll_watcher(ctx);
+        if let Some(covers) = self.covers.as_mut(){
+            covers.poll();
+        }
+        if self.albums_dirty && self.albums_rebuilt_at.elapsed() > std::time::Duration::from_millis(500) {
+            self.rebuild_albums();
+        }
+        if self.smart_dirty {
+            self.evaluate_smart_playlists();
+        }
+
+        self.update_crossfade();
+        self.update_preload();
+        if self.audio_sink.empty() && !self.waiting_for_play {
             self.play_next();
         }
+        let current_uid = self.queue_indices.get(self.queue_current_position).filter(|e| Some(e.song) == self.current_song).map(|e| e.uid);
+        let record = !std::mem::take(&mut self.leaving_via_back);
+        if let Some(played) = self.history.update(self.current_song, current_uid, self.playing, record) {
+            if let Some(song) = self.song_info.get(played) {
+                *self.play_counts.entry(song.path.clone()).or_default() += 1;
+                self.smart_dirty = true;
+            }
+        }
+
+        if self.playing {
+            if let Some(song) = self.current_song.and_then(|id| self.song_info.get(id)) {
+                self.progress = self.audio_sink.get_pos().as_secs_f32() / song.duration.as_secs_f32();
+            }
+        }
+
+        //Top bar, open settings
+        egui::TopBottomPanel::top("settings").show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                if let Some((id, song)) = self.current_song.and_then(|id| self.song_info.get(id).cloned().map(|s| (id, s))){
+                    let (cover_rect, _) = ui.allocate_exact_size(egui::vec2(48.0, 48.0), egui::Sense::hover());
+                    match self.song_cover(id) {
+                        Some(cover) => PlayerApp::paint_cover(ui, &cover, cover_rect),
+                        None => {
+                            ui.painter().rect_filled(cover_rect, 4.0, ui.visuals().widgets.inactive.bg_fill);
+                        }
+                    }
+                    ui.vertical(|ui| {
+                        ui.label(song.track.clone() + " - " + &song.artist);
+                        ui.weak(song.album.clone());
+                        ui.weak(song.format_summary());
+                    });
+                }
+                ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+                    if ui.button("Directories").clicked(){
+                        self.show_dirs = true;
+                    }
+                    if ui.button("Playback").clicked(){
+                        self.show_playback = true;
+                    }
+                    if ui.button("History").clicked(){
+                        self.show_history = true;
+                    }
+                    if ui.selectable_label(self.show_playlists, "Playlists").clicked(){
+                        self.show_playlists = !self.show_playlists;
+                    }
+                    ui.menu_button("M3U", |ui| {
+                        if ui.button("Import into Queue...").clicked(){
+                            self.import_m3u_to_queue();
+                        }
+                        if ui.add_enabled(!self.queue_indices.is_empty(), egui::Button::new("Export Queue...")).clicked(){
+                            let songs: Vec<SongId> = self.queue_indices.iter().map(|e| e.song).collect();
+                            self.export_m3u(&songs, "queue");
+                        }
+                        ui.checkbox(&mut self.m3u_relative, "Export relative paths")
+                            .on_hover_text("Paths relative to the playlist's folder, so it keeps working when moved to another machine along with the music");
+                    });
+                    if let Some(job) = &self.scan_job {
+                        ui.spinner();
+                        ui.label(format!("Scanning {}/{}", job.done, job.total));
+                    }
+                });
+            });
+        });
+
+        //Directory management
+        if self.show_dirs {
+            let mut open = true;
+            let mut queue_scan = false;
+            let mut rescan = false;
+            let mut remove: Option<usize> = None;
+            egui::Window::new("dir_list").open(&mut open)
+                .show(ctx, |ui| {
+                    ui.horizontal(|ui|{
+                        if ui.button("Add Directory").clicked() {
+                            queue_scan = true;
+                        }
+                        if ui.button("Rescan").clicked() {
+                            rescan = true;
+                        }
+                    });
+                    ui.horizontal(|ui|{
+                        ui.label("Max depth:");
+                        ui.add(egui::DragValue::new(&mut self.scan_options.max_depth).range(0..=64));
+                        ui.checkbox(&mut self.scan_options.skip_hidden, "Skip hidden");
+                        if ui.button("Tag Patterns").clicked() {
+                            self.show_tag_patterns = true;
+                        }
+                    });
+
+                    if let Some(job) = &self.scan_job {
+                        ui.separator();
+                        ui.horizontal(|ui|{
+                            let fraction = if job.total > 0 { job.done as f32 / job.total as f32 } else { 0.0 };
+                            ui.add(egui::ProgressBar::new(fraction).desired_width(250.0).text(format!("{}/{}", job.done, job.total)));
+                            if job.is_cancelled() {
+                                ui.label("Cancelling...");
+                            }else if ui.button("Cancel").clicked() {
+                                job.cancel();
+                            }
+                        });
+                        ui.label(PlayerApp::ellipsize(job.current.to_string_lossy().to_string(), 60));
+                        ui.separator();
+                    }
+                    
+
+                    ui.label("Directories:");
+                    for i in 0..self.dirs.len(){
+                        let dir_str = self.dirs[i].to_string_lossy();
+                        ui.horizontal(|ui|{
+                            if ui.button("X").clicked(){
+                                remove = Some(i);
+                            }
+                            ui.label(dir_str);
+                        });
+                    }
+
+                    let error_count = self.scan_errors.iter().filter(|e| e.kind != scan::ScanErrorKind::Unsupported).count();
+                    if error_count > 0 {
+                        ui.separator();
+                        if ui.button(format!("Scan Errors ({})", error_count)).clicked() {
+                            self.show_scan_errors = true;
+                        }
+                    }
+                });                    
+            if let Some(i) = remove {
+                self.remove_directory(i);
+            }
+            if queue_scan {
+                self.select_folder_and_scan(ctx);
+            }
+            if rescan {
+                self.rescan_library(ctx);
+            }
+            self.show_dirs = open;
+        }
+
+        //Recently played
+        if self.show_history {
+            let mut open = true;
+            let mut replay: Option<SongId> = None;
+            let mut requeue: Option<SongId> = None;
+            let mut remove: Option<usize> = None;
+            let mut clear = false;
+            egui::Window::new("History").open(&mut open).default_width(450.0)
+                .show(ctx, |ui| {
+                    if self.history.is_empty() {
+                        ui.weak("Nothing played yet.");
+                        return;
+                    }
+                    if ui.button("Clear History").clicked() {
+                        clear = true;
+                    }
+                    ui.separator();
+                    egui::ScrollArea::vertical().max_height(400.0).show(ui, |ui| {
+                        egui::Grid::new("history_grid").striped(true).show(ui, |ui| {
+                            let entries: Vec<(usize, history::HistoryEntry)> = self.history.iter().cloned().enumerate().rev().collect();
+                            for (i, entry) in entries {
+                                let Some(song) = self.song_info.get(entry.song) else {
+                                    continue;
+                                };
+                                ui.weak(history::format_ago(entry.played_at));
+                                let res = ui.add(egui::Label::new(format!("{} - {}", song.track, song.artist)).truncate().sense(egui::Sense::click()))
+                                    .on_hover_text("Click to play again");
+                                let listened = entry.listened.as_secs();
+                                ui.weak(format!("{}:{:02}", listened / 60, listened % 60));
+                                ui.end_row();
+
+                                if res.clicked() {
+                                    replay = Some(entry.song);
+                                }
+                                res.context_menu(|ui| {
+                                    if ui.button("Play").clicked() {
+                                        replay = Some(entry.song);
+                                        ui.close();
+                                    }
+                                    if ui.button("Queue Song").clicked() {
+                                        requeue = Some(entry.song);
+                                        ui.close();
+                                    }
+                                    if ui.button("Remove from History").clicked() {
+                                        remove = Some(i);
+                                        ui.close();
+                                    }
+                                });
+                            }
+                        });
+                    });
+                });
+            if let Some(id) = replay {
+                self.play_now(id);
+            }
+            if let Some(id) = requeue {
+                self.add_song_to_queue(id);
+            }
+            if let Some(i) = remove {
+                self.history.remove(i);
+            }
+            if clear {
+                self.history.clear();
+            }
+            self.show_history = open;
+        }
 
-        if self.playing {
-            self.progress = self.audio_sink.get_pos().as_secs_f32() / self.song_info[self.song_current_position.unwrap()].duration.as_secs_f32();
+        //Songs an import couldn't find
+        if let Some(report) = self.import_report.clone() {
+            let mut open = true;
+            egui::Window::new("Playlist Import").open(&mut open).collapsible(false).resizable(false)
+                .show(ctx, |ui| {
+                    ui.label(report);
+                });
+            if !open {
+                self.import_report = None;
+            }
         }
 
-        //Top bar, open settings
-        egui::TopBottomPanel::top("settings").show(ctx, |ui| {
-            ui.horizontal(|ui| {
-                if self.song_current_position.is_some(){
-                    let song = self.song_info[self.song_current_position.unwrap()].clone();
+        //Playback settings
+        if self.show_playback {
+            let mut open = true;
+            egui::Window::new("Playback").open(&mut open)
+                .show(ctx, |ui| {
+                    ui.horizontal(|ui|{
+                        ui.label("Crossfade:");
+                        ui.add(egui::Slider::new(&mut self.crossfade_secs, 0.0..=12.0).step_by(0.5).suffix(" s"));
+                    });
+                    if self.crossfade_secs <= 0.0 {
+                        ui.weak("Off, tracks follow each other gapless.");
+                    }
+                    ui.add_enabled_ui(self.crossfade_secs > 0.0, |ui|{
+                        egui::ComboBox::from_label("Curve")
+                            .selected_text(self.crossfade_curve.label())
+                            .show_ui(ui, |ui| {
+                                for curve in fade::FadeCurve::ALL {
+                                    ui.selectable_value(&mut self.crossfade_curve, curve, curve.label());
+                                }
+                            });
+                        ui.checkbox(&mut self.crossfade_skip_album, "No crossfade within an album");
+                    });
+                    ui.separator();
+                    egui::ComboBox::from_label("ReplayGain")
+                        .selected_text(self.gain_mode.label())
+                        .show_ui(ui, |ui| {
+                            for mode in replaygain::GainMode::ALL {
+                                ui.selectable_value(&mut self.gain_mode, mode, mode.label());
+                            }
+                        });
+                    if self.gain_mode == replaygain::GainMode::Auto {
+                        ui.weak("Album gain for albums queued as a whole, track gain otherwise.");
+                    }
+                    ui.checkbox(&mut self.prevent_clipping, "Prevent clipping");
+                    ui.weak("Changes apply from the next track on.");
 
-                    ui.label(song.track + " - " + &song.artist);
-                }
-                ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
-                    if ui.button("Directories").clicked(){
-                        self.show_dirs = true;
+                    ui.separator();
+                    ui.label("Loudness analysis");
+                    ui.weak("Measures albums with songs that have no ReplayGain tags. Stopping keeps every song measured so far.");
+                    let mut start_analysis = false;
+                    match &self.analysis_job {
+                        Some(job) => {
+                            ui.horizontal(|ui|{
+                                let fraction = if job.total > 0 { job.done as f32 / job.total as f32 } else { 0.0 };
+                                ui.add(egui::ProgressBar::new(fraction).desired_width(250.0).text(format!("{}/{}", job.done, job.total)));
+                                if job.is_cancelled() {
+                                    ui.label("Cancelling...");
+                                }else if ui.button("Cancel").clicked() {
+                                    job.cancel();
+                                }
+                            });
+                            ui.label(PlayerApp::ellipsize(job.current.to_string_lossy().to_string(), 60));
+                        }
+                        None => {
+                            ui.add_enabled_ui(self.scan_job.is_none(), |ui|{
+                                if ui.button("Analyze Library").on_disabled_hover_text("Waiting for the scan to finish").clicked() {
+                                    start_analysis = true;
+                                }
+                            });
+                        }
                     }
+                    if start_analysis {
+                        self.start_analysis(ctx);
+                    }
+                    ui.checkbox(&mut self.analysis_writeback, "Write results to tags")
+                        .on_hover_text("Only for songs that have tags, untagged files are left alone");
                 });
-            });
-        });
+            self.show_playback = open;
+        }
 
-        //Directory management
-        if self.show_dirs {
+        //Filename patterns for untagged files
+        if self.show_tag_patterns {
             let mut open = true;
-            let mut queue_scan = false;
-            let mut remove: Option<usize> = None;
-            egui::Window::new("dir_list").open(&mut open)
+            egui::Window::new("Tag Patterns").open(&mut open).default_width(450.0)
                 .show(ctx, |ui| {
-                    if ui.button("Add Directory").clicked() {
-                        queue_scan = true;
-                    } 
-                    
-                    ui.label("Directories:");
-                    for i in 0..self.dirs.len(){
-                        let dir_str = self.dirs[i].to_string_lossy();
+                    ui.label("Untagged files are named after the first pattern that matches the end of their path. Changes apply on the next rescan.");
+                    ui.weak("Fields: %artist% %albumartist% %album% %title% %track% %disc% %year% %genre% %_%");
+                    ui.separator();
+
+                    let mut remove: Option<usize> = None;
+                    let mut move_up: Option<usize> = None;
+                    let patterns = &mut self.scan_options.tag_patterns;
+                    for (i, text) in patterns.iter_mut().enumerate() {
                         ui.horizontal(|ui|{
-                            if ui.button("X").clicked(){
+                            if ui.button("X").clicked() {
                                 remove = Some(i);
                             }
-                            ui.label(dir_str);
+                            if ui.add_enabled(i > 0, egui::Button::new("⏶")).clicked() {
+                                move_up = Some(i);
+                            }
+                            ui.add(TextEdit::singleline(text).desired_width(300.0));
+                            if let Err(e) = pattern::TagPattern::parse(text) {
+                                ui.colored_label(Color32::LIGHT_RED, e);
+                            }
                         });
                     }
+                    if let Some(i) = remove {
+                        patterns.remove(i);
+                    }
+                    if let Some(i) = move_up {
+                        patterns.swap(i, i - 1);
+                    }
+                    ui.horizontal(|ui|{
+                        if ui.button("Add Pattern").clicked() {
+                            patterns.push("%artist% - %title%".to_string());
+                        }
+                        if ui.button("Reset").clicked() {
+                            *patterns = pattern::default_patterns();
+                        }
+                    });
 
-                });                    
-            if remove.is_some(){
-                self.dirs.remove(remove.unwrap());
-            }
-            if queue_scan {
-                self.select_folder_and_scan();
+                    ui.separator();
+                    ui.label("Test path, relative to a library directory:");
+                    ui.add(TextEdit::singleline(&mut self.pattern_test_path).desired_width(f32::INFINITY));
+                    let test_path = std::path::Path::new(&self.pattern_test_path);
+                    let matched = patterns.iter().enumerate()
+                        .find_map(|(i, p)| pattern::TagPattern::parse(p).ok()?.apply(test_path).map(|tags| (i, tags)));
+                    match matched {
+                        Some((i, tags)) => {
+                            ui.label(format!("Matched pattern {}: {}", i + 1, patterns[i]));
+                            egui::Grid::new("pattern_preview").show(ui, |ui| {
+                                let fields = [
+                                    ("Artist", tags.artist),
+                                    ("Album artist", tags.album_artist),
+                                    ("Album", tags.album),
+                                    ("Title", tags.title),
+                                    ("Track", tags.track.map(|n| n.to_string())),
+                                    ("Disc", tags.disc.map(|n| n.to_string())),
+                                    ("Year", tags.year.map(|n| n.to_string())),
+                                    ("Genre", tags.genre),
+                                ];
+                                for (name, value) in fields {
+                                    if let Some(value) = value {
+                                        ui.label(name);
+                                        ui.label(value);
+                                        ui.end_row();
+                                    }
+                                }
+                            });
+                        }
+                        None => {
+                            ui.colored_label(Color32::LIGHT_RED, "No pattern matches, the file name would become the title.");
+                        }
+                    }
+                });
+            self.show_tag_patterns = open;
+        }
+
+        //Scan error report
+        if self.show_scan_errors {
+            let mut open = true;
+            let mut export = false;
+            egui::Window::new("Scan Errors").open(&mut open).default_width(600.0)
+                .show(ctx, |ui| {
+                    ui.horizontal(|ui|{
+                        if ui.button("Export...").clicked() {
+                            export = true;
+                        }
+                        ui.checkbox(&mut self.show_unsupported_files, "Show unsupported files");
+                    });
+                    ui.separator();
+                    egui::ScrollArea::vertical().auto_shrink([false, true]).max_height(400.0).show(ui, |ui| {
+                        egui::Grid::new("scan_error_grid").striped(true).show(ui, |ui| {
+                            for error in &self.scan_errors {
+                                if error.kind == scan::ScanErrorKind::Unsupported && !self.show_unsupported_files {
+                                    continue;
+                                }
+                                ui.label(error.kind.label());
+                                ui.label(error.path.to_string_lossy()).on_hover_text(&error.reason);
+                                ui.label(PlayerApp::ellipsize(error.reason.clone(), 60));
+                                ui.end_row();
+                            }
+                        });
+                    });
+                });
+            if export {
+                self.export_scan_errors();
             }
-            self.show_dirs = open;
+            self.show_scan_errors = open;
         }
 
         //Player controls
@@ -479,7 +2017,10 @@ impl eframe::App for PlayerApp {
                     }
                 }
                 if ui.button("⏭").clicked(){
-                    self.audio_sink.clear();
+                    self.skip_forward();
+                }
+                if ui.selectable_label(self.repeat != RepeatMode::Off, self.repeat.icon()).on_hover_text(self.repeat.label()).clicked(){
+                    self.repeat = self.repeat.cycle();
                 }
             });
 
@@ -496,18 +2037,16 @@ impl eframe::App for PlayerApp {
                     
                     if response.drag_stopped() {
                         if self.queue_indices.len() > 0 {
-                            let song_len = self.song_info[self.song_current_position.unwrap()].duration.as_secs_f32();
-                            self.seek_to(self.progress * song_len);
+                            if let Some(song) = self.current_song.and_then(|id| self.song_info.get(id)) {
+                                let song_len = song.duration.as_secs_f32();
+                                self.seek_to(self.progress * song_len);
+                            }
                         }else{
                             self.progress = 0.0;
                         }
                     }
 
-                    let total_time = if self.song_current_position.is_some() {
-                        self.song_info[self.song_current_position.unwrap()].duration.as_secs()
-                    }else{
-                        0
-                    };
+                    let total_time = self.current_song.and_then(|id| self.song_info.get(id)).map(|s| s.duration.as_secs()).unwrap_or(0);
                     let pred_time = (self.progress * total_time as f32) as u64;
                     let time_string = std::format!("{:}:{:02} / {:}:{:02}", pred_time/60, pred_time%60, total_time/60, total_time%60);
                     ui.label(time_string);
@@ -517,6 +2056,9 @@ impl eframe::App for PlayerApp {
                     ui.with_layout(egui::Layout::left_to_right(egui::Align::Center), |ui| {
                         ui.add(egui::Slider::new(&mut self.volume, 0.0..=1.0).show_value(false).text(volume_string));
                         self.audio_sink.set_volume(self.volume);
+                        for sink in &self.fading_sinks {
+                            sink.set_volume(self.volume);
+                        }
                     }); 
                 });
                 });
@@ -524,8 +2066,344 @@ impl eframe::App for PlayerApp {
             });
         });
         
+        //Saved playlists
+        if self.show_playlists {
+            egui::SidePanel::left("playlists_panel").resizable(true).default_width(220.0).show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    ui.label("Playlists");
+                    if ui.button("New").clicked() {
+                        self.apply_playlist_pick(PlaylistPick::New, &[]);
+                    }
+                    if ui.button("Import...").clicked() {
+                        self.import_m3u_to_playlist();
+                    }
+                });
+                ui.separator();
+
+                let mut action: Option<(u64, PlaylistAction)> = None;
+                let mut commit_rename = false;
+                egui::ScrollArea::vertical().id_salt("Playlists").max_height(ui.available_height() / 2.0).auto_shrink([false, true]).show(ui, |ui| {
+                    egui_dnd::dnd(ui, "dnd_playlists").show_vec(&mut self.playlists.lists, |ui, playlist, handle, _state| {
+                        handle.ui(ui, |ui| {
+                            if let Some((_, name)) = self.renaming_playlist.as_mut().filter(|(id, _)| *id == playlist.id) {
+                                let res = ui.text_edit_singleline(name);
+                                if !res.has_focus() && !res.lost_focus() {
+                                    res.request_focus();
+                                }
+                                if res.lost_focus() {
+                                    commit_rename = true;
+                                }
+                                return;
+                            }
+                            let open = self.open_playlist == Some(playlist.id);
+                            let res = ui.add(egui::Button::selectable(open, format!("{} ({})", playlist.name, playlist.songs.len())).truncate())
+                                .on_hover_text("Double click to play");
+                            if res.clicked() {
+                                self.open_playlist = if open { None } else { Some(playlist.id) };
+                            }
+                            if res.double_clicked() {
+                                action = Some((playlist.id, PlaylistAction::Play));
+                            }
+                            res.context_menu(|ui| {
+                                let items = [
+                                    ("Play", PlaylistAction::Play),
+                                    ("Append to Queue", PlaylistAction::Append),
+                                    ("Rename", PlaylistAction::Rename),
+                                    ("Duplicate", PlaylistAction::Duplicate),
+                                    ("Export M3U8...", PlaylistAction::Export),
+                                    ("Delete", PlaylistAction::Delete),
+                                ];
+                                for (label, item) in items {
+                                    if ui.button(label).clicked() {
+                                        action = Some((playlist.id, item));
+                                        ui.close();
+                                    }
+                                }
+                            });
+                        });
+                    });
+
+                    ui.horizontal(|ui| {
+                        ui.label("Smart Playlists");
+                        if ui.button("New").clicked() {
+                            self.editing_smart = Some(SmartEditor { playlist: SmartPlaylist::default(), is_new: true, matches: None });
+                        }
+                    });
+                    for playlist in &self.playlists.smart {
+                        let open = self.open_playlist == Some(playlist.id);
+                        let count = self.smart_results.get(&playlist.id).map(|songs| songs.len()).unwrap_or(0);
+                        let res = ui.add(egui::Button::selectable(open, format!("{} ({})", playlist.name, count)).truncate())
+                            .on_hover_text("Double click to play");
+                        if res.clicked() {
+                            self.open_playlist = if open { None } else { Some(playlist.id) };
+                        }
+                        if res.double_clicked() {
+                            action = Some((playlist.id, PlaylistAction::Play));
+                        }
+                        res.context_menu(|ui| {
+                            let items = [
+                                ("Play", PlaylistAction::Play),
+                                ("Append to Queue", PlaylistAction::Append),
+                                ("Edit Rules...", PlaylistAction::Edit),
+                                ("Duplicate", PlaylistAction::Duplicate),
+                                ("Export M3U8...", PlaylistAction::Export),
+                                ("Delete", PlaylistAction::Delete),
+                            ];
+                            for (label, item) in items {
+                                if ui.button(label).clicked() {
+                                    action = Some((playlist.id, item));
+                                    ui.close();
+                                }
+                            }
+                        });
+                    }
+                });
+                if commit_rename {
+                    if let Some((id, name)) = self.renaming_playlist.take() {
+                        self.playlists.rename(id, &name);
+                    }
+                }
+                if let Some((id, action)) = action {
+                    match action {
+                        PlaylistAction::Play => self.play_playlist(id, 0),
+                        PlaylistAction::Append => {
+                            for song in self.playlist_songs(id) {
+                                self.add_song_to_queue(song);
+                            }
+                        }
+                        PlaylistAction::Rename => self.renaming_playlist = self.playlists.get(id).map(|p| (id, p.name.clone())),
+                        PlaylistAction::Duplicate => {
+                            self.open_playlist = self.playlists.duplicate(id);
+                            self.smart_dirty = true;
+                        }
+                        PlaylistAction::Export => {
+                            let name = self.playlists.name(id).unwrap_or_default().to_string();
+                            self.export_m3u(&self.playlist_songs(id), &name);
+                        }
+                        PlaylistAction::Delete => self.deleting_playlist = Some(id),
+                        PlaylistAction::Edit => {
+                            self.editing_smart = self.playlists.get_smart(id)
+                                .map(|p| SmartEditor { playlist: p.clone(), is_new: false, matches: None });
+                        }
+                    }
+                }
+
+                if let Some(id) = self.open_playlist.filter(|id| self.playlists.get_smart(*id).is_some()) {
+                    ui.separator();
+                    self.smart_playlist_ui(ui, id);
+                    return;
+                }
+
+                //songs of the open playlist
+                let Some(id) = self.open_playlist.filter(|id| self.playlists.get(*id).is_some()) else {
+                    return;
+                };
+                ui.separator();
+                ui.horizontal(|ui| {
+                    if ui.button("Play").clicked() {
+                        self.play_playlist(id, 0);
+                    }
+                    if ui.button("Append to Queue").clicked() {
+                        for song in self.playlist_songs(id) {
+                            self.add_song_to_queue(song);
+                        }
+                    }
+                });
+                let mut play_from: Option<usize> = None;
+                let mut queue: Option<SongId> = None;
+                let mut remove: Option<usize> = None;
+                egui::ScrollArea::vertical().id_salt("PlaylistSongs").auto_shrink([false, false]).show(ui, |ui| {
+                    let Some(playlist) = self.playlists.get_mut(id) else {
+                        return;
+                    };
+                    if playlist.songs.is_empty() {
+                        ui.weak("Add songs from the library's right click menu.");
+                    }
+                    egui_dnd::dnd(ui, "dnd_playlist_songs").show_vec(&mut playlist.songs, |ui, entry, handle, state| {
+                        handle.ui(ui, |ui| {
+                            let song_id = self.song_info.id_for_path(&entry.path);
+                            let res = match song_id.and_then(|id| self.song_info.get(id)) {
+                                Some(song) => ui.add(egui::Button::selectable(song_id == self.current_song, format!("{} - {}", song.track, song.artist)).truncate())
+                                    .on_hover_ui(|ui| { ui.label(song.details()); }),
+                                //kept around in case it's only missing until the next scan
+                                None => ui.add(egui::Button::selectable(false, egui::RichText::new(entry.path.to_string_lossy()).weak()).truncate())
+                                    .on_hover_text("Not in the library"),
+                            };
+                            if res.double_clicked() {
+                                play_from = Some(state.index);
+                            }
+                            res.context_menu(|ui| {
+                                if ui.add_enabled(song_id.is_some(), egui::Button::new("Play from Here")).clicked() {
+                                    play_from = Some(state.index);
+                                    ui.close();
+                                }
+                                if ui.add_enabled(song_id.is_some(), egui::Button::new("Queue Song")).clicked() {
+                                    queue = song_id;
+                                    ui.close();
+                                }
+                                if ui.button("Remove from Playlist").clicked() {
+                                    remove = Some(state.index);
+                                    ui.close();
+                                }
+                            });
+                        });
+                    });
+                });
+                if let Some(index) = play_from {
+                    self.play_playlist(id, index);
+                }
+                if let Some(song) = queue {
+                    self.add_song_to_queue(song);
+                }
+                if let Some(index) = remove {
+                    if let Some(playlist) = self.playlists.get_mut(id) {
+                        playlist.songs.remove(index);
+                    }
+                }
+            });
+        }
+
+        //Smart playlist rules
+        if let Some(mut editor) = self.editing_smart.take() {
+            let mut open = true;
+            let mut save = false;
+            let mut cancel = false;
+            let before = editor.playlist.clone();
+            let title = if editor.is_new { "New Smart Playlist" } else { "Edit Smart Playlist" };
+            egui::Window::new(title).open(&mut open).default_width(480.0)
+                .show(ctx, |ui| {
+                    let playlist = &mut editor.playlist;
+                    ui.horizontal(|ui| {
+                        ui.label("Name");
+                        ui.text_edit_singleline(&mut playlist.name);
+                    });
+                    ui.horizontal(|ui| {
+                        ui.label("Match");
+                        egui::ComboBox::from_id_salt("smart_match")
+                            .selected_text(if playlist.match_all { "all" } else { "any" })
+                            .show_ui(ui, |ui| {
+                                ui.selectable_value(&mut playlist.match_all, true, "all");
+                                ui.selectable_value(&mut playlist.match_all, false, "any");
+                            });
+                        ui.label("of these rules:");
+                    });
+
+                    let mut remove: Option<usize> = None;
+                    egui::Grid::new("smart_rules").show(ui, |ui| {
+                        for (i, rule) in playlist.rules.iter_mut().enumerate() {
+                            let mut field = rule.field;
+                            egui::ComboBox::from_id_salt(("smart_field", i))
+                                .selected_text(field.label())
+                                .show_ui(ui, |ui| {
+                                    for f in smart::Field::ALL {
+                                        ui.selectable_value(&mut field, f, f.label());
+                                    }
+                                });
+                            if field != rule.field {
+                                rule.set_field(field);
+                            }
+                            egui::ComboBox::from_id_salt(("smart_operator", i))
+                                .selected_text(rule.operator.label())
+                                .show_ui(ui, |ui| {
+                                    for operator in smart::Operator::for_kind(rule.field.kind()) {
+                                        ui.selectable_value(&mut rule.operator, *operator, operator.label());
+                                    }
+                                });
+                            ui.add(TextEdit::singleline(&mut rule.value).desired_width(120.0));
+                            ui.label(rule.field.unit());
+                            if ui.small_button("✖").on_hover_text("Remove rule").clicked() {
+                                remove = Some(i);
+                            }
+                            ui.end_row();
+                        }
+                    });
+                    if let Some(i) = remove {
+                        playlist.rules.remove(i);
+                    }
+                    if ui.button("Add Rule").clicked() {
+                        playlist.rules.push(smart::Rule::default());
+                    }
+
+                    ui.separator();
+                    ui.horizontal(|ui| {
+                        ui.label("Sort by");
+                        egui::ComboBox::from_id_salt("smart_sort")
+                            .selected_text(playlist.sort.label())
+                            .show_ui(ui, |ui| {
+                                for sort in smart::SmartSort::ALL {
+                                    ui.selectable_value(&mut playlist.sort, sort, sort.label());
+                                }
+                            });
+                        ui.checkbox(&mut playlist.descending, "Descending");
+                    });
+                    ui.horizontal(|ui| {
+                        let mut limited = playlist.limit.is_some();
+                        let mut limit = playlist.limit.unwrap_or(25);
+                        ui.checkbox(&mut limited, "Limit to");
+                        ui.add_enabled(limited, egui::DragValue::new(&mut limit).range(1..=100_000));
+                        ui.label("songs");
+                        playlist.limit = limited.then_some(limit);
+                    });
+
+                    ui.separator();
+                    if let Some(matches) = editor.matches {
+                        ui.weak(format!("{} songs match", matches));
+                    }
+                    ui.horizontal(|ui| {
+                        if ui.button("Save").clicked() {
+                            save = true;
+                        }
+                        if ui.button("Cancel").clicked() {
+                            cancel = true;
+                        }
+                    });
+                });
+            //counted again only when something changed, not every frame
+            if editor.matches.is_none() || editor.playlist != before {
+                let context = RuleContext { play_counts: &self.play_counts, now: std::time::SystemTime::now() };
+                editor.matches = Some(editor.playlist.evaluate(&self.song_info, &context).len());
+            }
+            if save {
+                if editor.is_new {
+                    self.open_playlist = Some(self.playlists.add_smart(editor.playlist));
+                    self.show_playlists = true;
+                } else {
+                    self.playlists.update_smart(editor.playlist);
+                }
+                self.smart_dirty = true;
+            } else if open && !cancel {
+                self.editing_smart = Some(editor);
+            }
+        }
+
+        //Deleting a playlist can't be undone, so it's asked first
+        if let Some(id) = self.deleting_playlist {
+            let name = self.playlists.name(id).unwrap_or_default().to_string();
+            let mut open = true;
+            let mut done = false;
+            egui::Window::new("Delete Playlist").open(&mut open).collapsible(false).resizable(false)
+                .show(ctx, |ui| {
+                    ui.label(format!("Delete \"{}\"?", name));
+                    ui.horizontal(|ui| {
+                        if ui.button("Delete").clicked() {
+                            self.playlists.delete(id);
+                            if self.open_playlist == Some(id) {
+                                self.open_playlist = None;
+                            }
+                            done = true;
+                        }
+                        if ui.button("Cancel").clicked() {
+                            done = true;
+                        }
+                    });
+                });
+            if !open || done {
+                self.deleting_playlist = None;
+            }
+        }
+
         //Library & Queue
-        let mut filtered_song_indices = Vec::new();
+        let mut filtered_songs: HashSet<SongId> = HashSet::new();
         egui::CentralPanel::default().show(ctx, |ui| {
             ui.with_layout(egui::Layout::top_down(egui::Align::Center), |ui| {
             ui.with_layout(egui::Layout::top_down_justified(egui::Align::Center), |ui| {
@@ -535,6 +2413,26 @@ impl eframe::App for PlayerApp {
                 TextEdit::singleline(&mut self.filter_text)
                     .hint_text("Search..."),
             );
+            if !type_res.has_focus() {
+                if ui.input_mut(|i| i.consume_key(egui::Modifiers::COMMAND, egui::Key::A)) {
+                    match self.selection_focus {
+                        SelectionFocus::Library => self.library_selection.select_all(&self.library_order),
+                        SelectionFocus::Queue => {
+                            let order: Vec<usize> = self.queue_indices.iter().map(|e| e.uid).collect();
+                            self.queue_selection.select_all(&order);
+                        }
+                    }
+                }
+                if ui.input(|i| i.key_pressed(egui::Key::Escape)) {
+                    self.library_selection.clear();
+                    self.queue_selection.clear();
+                }
+                if self.selection_focus == SelectionFocus::Queue && !self.queue_selection.is_empty() && ui.input(|i| i.key_pressed(egui::Key::Delete)) {
+                    let uids = self.queue_selection.items().clone();
+                    self.remove_queue_entries(&uids);
+                    self.queue_selection.clear();
+                }
+            }
             if !type_res.has_focus() && ui.input(|i| i.key_pressed(egui::Key::Space)){
                 if self.playing {
                     self.pause();
@@ -544,19 +2442,19 @@ impl eframe::App for PlayerApp {
             }
 
             if !self.filter_text.eq(""){
-                for i in 0..self.song_info.len(){
-                    if self.song_info[i].track.to_lowercase().contains(&self.filter_text.to_lowercase()){
-                        filtered_song_indices.push(i);
-                    }else if self.song_info[i].artist.to_lowercase().contains(&self.filter_text.to_lowercase()){
-                        filtered_song_indices.push(i);
-                    }else if self.song_info[i].album.to_lowercase().contains(&self.filter_text.to_lowercase()){
-                        filtered_song_indices.push(i);
+                let filter = self.filter_text.to_lowercase();
+                for (id, song) in self.song_info.iter(){
+                    let optional_fields = [&song.album_artist, &song.genre, &song.composer];
+                    if song.track.to_lowercase().contains(&filter)
+                        || song.artist.to_lowercase().contains(&filter)
+                        || song.album.to_lowercase().contains(&filter)
+                        || optional_fields.iter().any(|f| f.as_ref().is_some_and(|f| f.to_lowercase().contains(&filter)))
+                        || song.year.is_some_and(|y| y.to_string() == filter){
+                        filtered_songs.insert(id);
                     }
                 }
             }else{
-                for i in 0..self.song_info.len(){
-                    filtered_song_indices.push(i);
-                }
+                filtered_songs.extend(self.song_info.ids());
             }
             });
 
@@ -578,10 +2476,25 @@ impl eframe::App for PlayerApp {
                         if ui.button("Shuffle Play").clicked(){
                             self.shuffle_play();
                         }
+                        egui::ComboBox::from_id_salt("shuffle_mode")
+                            .selected_text(self.shuffle_mode.label())
+                            .show_ui(ui, |ui| {
+                                for mode in ShuffleMode::ALL {
+                                    ui.selectable_value(&mut self.shuffle_mode, mode, mode.label());
+                                }
+                            });
+                        egui::ComboBox::from_id_salt("library_sort")
+                            .selected_text(format!("Sort: {}", self.library_sort.label()))
+                            .show_ui(ui, |ui| {
+                                for sort in LibrarySort::ALL {
+                                    ui.selectable_value(&mut self.library_sort, sort, sort.label());
+                                }
+                            });
                     });
                     ui.separator();
                     egui::ScrollArea::vertical().auto_shrink([false, false]).id_salt("LoadedSongs").show(ui, |ui| {
-                        for (album_hash, album_vec) in self.library.albums.clone().into_iter(){
+                        let mut library_order = Vec::new();
+                        for (album_hash, album_vec) in self.sorted_albums(){
                             let (album, artist) = album_hash.clone();
                             let desired_size = egui::vec2(ui.available_width() - 15.0, 24.0);
                             let ar_string = album.clone() + " - " + &artist.clone();
@@ -591,24 +2504,28 @@ impl eframe::App for PlayerApp {
                             let approx_char_width = (font_size * 0.6).max(4.0);
                             let max_chars = (col_width / approx_char_width).floor() as usize;
 
-                            let mut filtered_album_indices: Vec<usize> = Vec::default();
-                            for song_index in album_vec.songs {    
-                                if None == filtered_song_indices.iter().find(|i| **i == song_index){
-                                    continue;
-                                }
-                                filtered_album_indices.push(song_index);
-                            }
-                            let skip_album = if filtered_album_indices.len() > 0 { false } else { true };
-                            if skip_album {
+                            let multi_disc = album_vec.is_multi_disc(&self.song_info);
+                            let cover_path = album_vec.songs.first().and_then(|id| self.song_info.get(*id)).map(|s| s.path.clone());
+                            let filtered_album_songs: Vec<SongId> = album_vec.songs.into_iter().filter(|id| filtered_songs.contains(id)).collect();
+                            if filtered_album_songs.is_empty() {
                                 continue;
                             }
+                            library_order.extend(filtered_album_songs.iter().copied());
                             ui.allocate_ui(desired_size, |ui|{
                                 let col_response = egui::CollapsingHeader::new("").id_salt(ar_string.as_str())
                                     .default_open(false)
                                     .open(Some(enabled))
                                     .show(ui, |ui| {
-                                        for song_index in filtered_album_indices {
-                                            let song = self.song_info[song_index].clone();
+                                        let mut last_disc = None;
+                                        for song_id in filtered_album_songs {
+                                            let Some(song) = self.song_info.get(song_id).cloned() else {
+                                                continue;
+                                            };
+                                            let disc = song.disc_number.unwrap_or(1);
+                                            if multi_disc && last_disc != Some(disc) {
+                                                ui.weak(format!("Disc {}", disc));
+                                                last_disc = Some(disc);
+                                            }
 
                                             let mut number = song.track_number.unwrap_or(usize::MAX);
                                             if number == usize::MAX{
@@ -622,8 +2539,12 @@ impl eframe::App for PlayerApp {
                                                 song.duration.as_secs() % 60
                                             );
                                             ui.allocate_ui_with_layout(ui.available_size(), egui::Layout::centered_and_justified(egui::Direction::TopDown), |ui|{
-                                                let res = ui.selectable_label(Some(song_index) == self.song_current_position, "");
+                                                let res = ui.selectable_label(Some(song_id) == self.current_song, "")
+                                                    .on_hover_ui(|ui| { ui.label(song.details()); });
                                                 let color = if res.hovered() { Color32::LIGHT_BLUE } else { Color32::LIGHT_GRAY };
+                                                if self.library_selection.contains(&song_id) {
+                                                    ui.painter().rect_stroke(res.rect, 4.0, ui.visuals().selection.stroke, egui::StrokeKind::Inside);
+                                                }
                                                 
                                                 ui.painter().text([res.rect.left() + 20.0, res.rect.center().y].into(), egui::Align2::LEFT_CENTER, 
                                                     label,
@@ -634,14 +2555,38 @@ impl eframe::App for PlayerApp {
                                                     egui::TextStyle::Body.resolve(ui.style()), color);         
 
                                                 if res.clicked() {
-                                                    self.queue_current_position = 0;
-                                                    self.queue_indices = Vec::new();                                
-                                                    self.add_song_to_queue_with_index(filtered_song_indices[song_index]);
-                                                    self.play_immediately_with_index(filtered_song_indices[song_index]);
+                                                    self.selection_focus = SelectionFocus::Library;
+                                                    let modifiers = ui.input(|i| i.modifiers);
+                                                    if self.library_selection.click(song_id, &self.library_order, modifiers) {
+                                                        self.queue_current_position = 0;
+                                                        self.queue_indices = Vec::new();
+                                                        self.shuffled = false;                                
+                                                        self.add_song_to_queue(song_id);
+                                                        self.play_immediately(song_id);
+                                                    }
                                                 }
                                                 res.context_menu(|ui| {
-                                                    if ui.button("Queue Song").clicked(){
-                                                        self.add_song_to_queue_with_index(filtered_song_indices[song_index]);
+                                                    //a row that's part of the selection acts for all of it
+                                                    let songs = if self.library_selection.covers(&song_id) {
+                                                        self.library_selection.ordered(&self.library_order)
+                                                    } else {
+                                                        vec![song_id]
+                                                    };
+                                                    let queue_label = if songs.len() > 1 { format!("Queue {} Songs", songs.len()) } else { "Queue Song".to_string() };
+                                                    if ui.button(queue_label).clicked(){
+                                                        for id in &songs {
+                                                            self.add_song_to_queue(*id);
+                                                        }
+                                                    }
+                                                    if ui.button("Play Next").clicked(){
+                                                        self.play_songs_next(&songs, false);
+                                                    }
+                                                    if ui.button("Play After Current Album").clicked(){
+                                                        self.play_songs_after_album(&songs, false);
+                                                    }
+                                                    if let Some(pick) = PlayerApp::playlist_menu(ui, &self.playlists, "Add to Playlist") {
+                                                        self.apply_playlist_pick(pick, &songs);
+                                                        ui.close();
                                                     }
                                                 });
                                             });
@@ -653,8 +2598,20 @@ impl eframe::App for PlayerApp {
                                 real_header_rect.max.x = ui.available_width() - 15.0;
                                 let real_header_res = ui.allocate_rect(real_header_rect, egui::Sense::click());
                                 let color = if real_header_res.hovered() { Color32::LIGHT_BLUE } else { Color32::WHITE };
-                                
-                                ui.painter().text([real_header_rect.left() + 20.0, real_header_rect.center().y].into(), egui::Align2::LEFT_CENTER, 
+
+                                //only albums on screen ask for their cover, so scrolling doesn't load the whole library
+                                let cover_size = real_header_rect.height() - 2.0;
+                                let cover_rect = egui::Rect::from_center_size(
+                                    [real_header_rect.left() + 20.0 + cover_size / 2.0, real_header_rect.center().y].into(),
+                                    egui::vec2(cover_size, cover_size));
+                                if ui.is_rect_visible(real_header_rect) {
+                                    let cover = cover_path.as_ref().zip(self.covers.as_mut()).and_then(|(path, covers)| covers.get(&album_hash, path));
+                                    if let Some(cover) = cover {
+                                        PlayerApp::paint_cover(ui, &cover, cover_rect);
+                                    }
+                                }
+
+                                ui.painter().text([cover_rect.right() + 6.0, real_header_rect.center().y].into(), egui::Align2::LEFT_CENTER, 
                                     PlayerApp::ellipsize(album.clone(), max_chars), 
                                     egui::TextStyle::Body.resolve(ui.style()), color);
                                 
@@ -663,19 +2620,33 @@ impl eframe::App for PlayerApp {
                                     egui::TextStyle::Body.resolve(ui.style()), color);         
 
                                 if real_header_res.clicked(){
-                                    self.enabled_album.insert(album_hash, !enabled);
+                                    self.enabled_album.insert(album_hash.clone(), !enabled);
                                 }
 
                                 real_header_res.context_menu(|ui| {
                                     if ui.button("Queue Album").clicked() {
-                                        if let Some(s) = self.song_info.iter().find(|s| s.album == album){
-                                            self.queue_album(s.clone());
-                                        }
-                                    ui.close(); 
-                                }
+                                        self.queue_album(&album_hash);
+                                        ui.close(); 
+                                    }
+                                    if ui.button("Queue Album Next").clicked() {
+                                        let songs = self.album_songs(&album_hash);
+                                        self.play_songs_next(&songs, true);
+                                        ui.close();
+                                    }
+                                    if ui.button("Queue Album After Current Album").clicked() {
+                                        let songs = self.album_songs(&album_hash);
+                                        self.play_songs_after_album(&songs, true);
+                                        ui.close();
+                                    }
+                                    if let Some(pick) = PlayerApp::playlist_menu(ui, &self.playlists, "Add Album to Playlist") {
+                                        let songs = self.album_songs(&album_hash);
+                                        self.apply_playlist_pick(pick, &songs);
+                                        ui.close();
+                                    }
                                 });                 
                             });
                         }
+                        self.library_order = library_order;
                     });
                 });
             });
@@ -686,13 +2657,20 @@ impl eframe::App for PlayerApp {
                 ui.with_layout(egui::Layout::top_down(egui::Align::Min),|ui|{
                     ui.allocate_ui(ui.available_size(), |ui|{
                         ui.horizontal(|ui|{
-                            ui.label("Queue");
+                            ui.label(if self.shuffled { "Queue (shuffled)" } else { "Queue" });
                             if ui.button("Clear").clicked(){
                                 self.clear_queue();
                             }
                             if ui.button("Shuffle Queue").clicked(){
                                 self.shuffle_queue();
                             }
+                            if ui.button("Save as Playlist").clicked(){
+                                let songs: Vec<SongId> = self.queue_indices.iter().map(|e| e.song).collect();
+                                self.apply_playlist_pick(PlaylistPick::New, &songs);
+                            }
+                            if self.shuffled && ui.button("Unshuffle").on_hover_text("Back to the order from before shuffling").clicked(){
+                                self.unshuffle_queue();
+                            }
                         });
                         
                         ui.separator();
@@ -700,29 +2678,47 @@ impl eframe::App for PlayerApp {
                             let mut remove: Option<usize> = None;
                             let mut immedate_queue: Option<usize> = None;                    
                             let mut immediate_play: Option<usize> = None;
-                            let inds = (self.song_current_position, self.queue_current_position);
+                            let mut play_next: Option<usize> = None;
+                            let mut to_playlist: Option<(usize, PlaylistPick)> = None;
+                            let mut clicked: Option<(usize, usize)> = None;
+                            let mut dragged_uid: Option<usize> = None;
+                            let current_uid = self.queue_indices.get(self.queue_current_position).map(|e| e.uid);
+                            let queue_order: Vec<usize> = self.queue_indices.iter().map(|e| e.uid).collect();
                             let response = egui_dnd::dnd(ui, "dnd_queue")
                                 .show_vec(&mut self.queue_indices, |ui, item, handle, state|{
                                 handle.ui(ui, |ui|{
                                     ui.set_width(ui.available_width() - 10.0);
-                                    let song_index = item.song_index;
-                                    let song = self.song_info[song_index].clone();
+                                    let song = self.song_info.get(item.song).cloned().unwrap_or_default();
                                     let col_width = (ui.available_width()) / 3.0;
                                     let font_size = ui.style().text_styles.get(&egui::TextStyle::Body).map(|p| p.size).unwrap_or(14.0);
                                     let approx_char_width = (font_size * 0.7).max(4.0);
                                     let max_chars = (col_width / approx_char_width).floor() as usize;
                                     let selected = state.index == self.queue_current_position;
+                                    if state.dragged {
+                                        dragged_uid = Some(item.uid);
+                                    }
                                     
                                     let (rect, response) = ui.allocate_exact_size([ui.available_width() - 35.0, 24.0].into(), egui::Sense::CLICK);
+                                    let response = response.on_hover_ui(|ui| { ui.label(song.details()); });
                                     if selected{
                                         ui.painter().rect_filled(rect, 4.0, ui.visuals().selection.bg_fill);
                                     }else{
                                         ui.painter().rect_filled(rect, 4.0, ui.visuals().widgets.inactive.bg_fill);
                                     }
+                                    if self.queue_selection.contains(&item.uid) {
+                                        ui.painter().rect_stroke(rect, 4.0, ui.visuals().selection.stroke, egui::StrokeKind::Inside);
+                                    }
                                     
                                     let spacing = 15.0;
                                     let text_y = rect.center().y;
-                                    let left = egui::pos2(rect.left() + spacing, text_y);
+                                    let cover_rect = egui::Rect::from_min_size(rect.left_top() + egui::vec2(4.0, 2.0), egui::vec2(rect.height() - 4.0, rect.height() - 4.0));
+                                    if ui.is_rect_visible(rect) {
+                                        let album = self.library.song_album.get(&item.song);
+                                        if let Some(cover) = album.zip(self.covers.as_mut()).and_then(|(album, covers)| covers.get(album, &song.path)) {
+                                            PlayerApp::paint_cover(ui, &cover, cover_rect);
+                                        }
+                                    }
+                                    let left = egui::pos2(cover_rect.right() + 8.0, text_y);
                                     let right = egui::pos2(rect.right() - spacing, text_y);
                                 
                                     if response.hovered(){
@@ -744,47 +2740,84 @@ impl eframe::App for PlayerApp {
                                     }
                                     
                                     if response.clicked() {
-                                        immediate_play = Some(state.index);
+                                        clicked = Some((state.index, item.uid));
                                     }
                                     response.context_menu(|ui|{
-                                        if ui.button("Queue Song").clicked() {
-                                            immedate_queue = Some(state.index);
+                                        let count = if self.queue_selection.covers(&item.uid) { self.queue_selection.len() } else { 1 };
+                                        let queue_label = if count > 1 { format!("Queue {} Songs", count) } else { "Queue Song".to_string() };
+                                        if ui.button(queue_label).clicked() {
+                                            immedate_queue = Some(item.uid);
                                             ui.close(); 
                                         }                                
-                                        if ui.button("Remove from Queue").clicked() {
-                                            remove = Some(state.index);
+                                        if ui.button("Play Next").clicked() {
+                                            play_next = Some(item.uid);
+                                            ui.close();
+                                        }
+                                        let remove_label = if count > 1 { format!("Remove {} from Queue", count) } else { "Remove from Queue".to_string() };
+                                        if ui.button(remove_label).clicked() {
+                                            remove = Some(item.uid);
                                             ui.close(); 
                                         }
+                                        if let Some(pick) = PlayerApp::playlist_menu(ui, &self.playlists, "Add to Playlist") {
+                                            to_playlist = Some((item.uid, pick));
+                                            ui.close();
+                                        }
                                     });
                                 });
                             });
 
                             if response.is_dragging() && self.playing {
-                                let curr_playing_idx = self.queue_indices.iter().position(|x| x.song_index == inds.0.unwrap());
-                                self.queue_current_position = curr_playing_idx.expect("failed to find current song in queue :SHOULDNT HAPPEN");
+                                if let Some(position) = self.queue_indices.iter().position(|x| Some(x.uid) == current_uid) {
+                                    self.queue_current_position = position;
+                                }
+                            }
+                            //the selection to bring along is taken when the drag starts, before it reorders anything
+                            if let Some(uid) = dragged_uid.filter(|_| self.queue_dragged.is_none()) {
+                                let selection = if self.queue_selection.covers(&uid) { self.queue_selection.ordered(&queue_order) } else { Vec::new() };
+                                self.queue_dragged = Some((uid, selection));
                             }
                             if response.is_drag_finished() {
                                 response.update_vec(&mut self.queue_indices);
+                                if let Some((uid, selection)) = self.queue_dragged.take() {
+                                    if !selection.is_empty() {
+                                        self.gather_queue_entries(uid, &selection);
+                                    }
+                                }
+                            } else if !response.is_dragging() {
+                                self.queue_dragged = None;
+                            }
+                            if let Some((index, uid)) = clicked {
+                                self.selection_focus = SelectionFocus::Queue;
+                                let modifiers = ui.input(|i| i.modifiers);
+                                if self.queue_selection.click(uid, &queue_order, modifiers) {
+                                    immediate_play = Some(index);
+                                }
                             }
                             if immediate_play.is_some(){
                                 self.queue_current_position = immediate_play.unwrap();
-                                self.play_immediately_with_index(self.queue_indices[immediate_play.unwrap()].song_index);
-                            }
-                            if immedate_queue.is_some(){
-                                self.add_song_to_queue_with_index(self.queue_indices[immedate_queue.unwrap()].song_index);
-                            }
-                            if remove.is_some(){
-                                println!("REmove");
-                                self.queue_indices.remove(remove.unwrap());
-                                if remove.unwrap() == self.queue_current_position{
-                                    println!("REmove is current position");
-                                    self.audio_sink.clear();
-                                    if self.queue_current_position < self.queue_indices.len() && self.queue_indices.len() > 1{
-                                        println!("remove valid restart");
-                                        self.play_immediately_with_index(self.queue_indices[self.queue_current_position].song_index);
-                                    }
+                                self.play_immediately(self.queue_indices[immediate_play.unwrap()].song);
+                            }
+                            //menu actions on a selected entry apply to the whole selection
+                            if let Some(uid) = immedate_queue {
+                                let uids = self.queue_selection.targets(uid);
+                                for id in self.queue_songs(&uids) {
+                                    self.add_song_to_queue(id);
                                 }
                             }
+                            if let Some(uid) = play_next {
+                                let uids = self.queue_selection.targets(uid);
+                                self.move_queue_entries_next(&uids);
+                            }
+                            if let Some((uid, pick)) = to_playlist {
+                                let uids = self.queue_selection.targets(uid);
+                                let songs = self.queue_songs(&uids);
+                                self.apply_playlist_pick(pick, &songs);
+                            }
+                            if let Some(uid) = remove {
+                                let uids = self.queue_selection.targets(uid);
+                                self.remove_queue_entries(&uids);
+                                self.queue_selection.clear();
+                            }
                         });
                     });
                 });
@@ -797,14 +2830,8 @@ impl eframe::App for PlayerApp {
         //Discord Integration
         if self.playing{
             //something playing
-            let mut should_update = false;
-            if currently_playing.is_none(){
-                should_update = true;
-            }else if currently_playing.unwrap() != self.song_current_position.unwrap(){
-                should_update = true;
-            }
-            if should_update{
-                    let song = self.song_info[self.song_current_position.unwrap()].clone();
+            let should_update = currently_playing != self.current_song;
+            if let Some(song) = self.current_song.and_then(|id| self.song_info.get(id)).cloned().filter(|_| should_update){
                     self.discord_client.set_activity(activity::Activity::new()
                         .activity_type(activity::ActivityType::Listening)
                         .state(&song.artist.clone())
@@ -832,9 +2859,46 @@ impl eframe::App for PlayerApp {
         let joined = self.dirs.iter().map(|p| p.to_string_lossy()).collect::<Vec<_>>().join(";");
         _storage.set_string("dirs", joined);
         _storage.set_string("vol", self.volume.to_string());
+        _storage.set_string("scan_depth", self.scan_options.max_depth.to_string());
+        _storage.set_string("scan_hidden", self.scan_options.skip_hidden.to_string());
+        _storage.set_string("library_sort", self.library_sort.label().to_string());
+        _storage.set_string("crossfade", self.crossfade_secs.to_string());
+        _storage.set_string("crossfade_curve", self.crossfade_curve.label().to_string());
+        _storage.set_string("crossfade_skip_album", self.crossfade_skip_album.to_string());
+        _storage.set_string("replaygain", self.gain_mode.label().to_string());
+        _storage.set_string("replaygain_clip", self.prevent_clipping.to_string());
+        _storage.set_string("analysis_writeback", self.analysis_writeback.to_string());
+        _storage.set_string("repeat", self.repeat.label().to_string());
+        //eframe also saves every so often while running, a restore that's still waiting has to survive that
+        let queue = match &self.pending_restore {
+            Some(pending) => ron::to_string(pending),
+            None => ron::to_string(&self.saved_queue()),
+        };
+        match queue {
+            Ok(queue) => _storage.set_string("queue", queue),
+            Err(e) => println!("Failed to save queue: {e}"),
+        }
+        _storage.set_string("tag_patterns", self.scan_options.tag_patterns.join("\n"));
+        _storage.set_string("shuffle_mode", self.shuffle_mode.label().to_string());
+        _storage.set_string("m3u_relative", self.m3u_relative.to_string());
+        _storage.set_string("show_playlists", self.show_playlists.to_string());
+        match self.playlists.save() {
+            Ok(playlists) => _storage.set_string("playlists", playlists),
+            Err(e) => println!("Failed to save playlists: {e}"),
+        }
+        match ron::to_string(&self.play_counts) {
+            Ok(counts) => _storage.set_string("play_counts", counts),
+            Err(e) => println!("Failed to save play counts: {e}"),
+        }
     }
 
     fn on_exit(&mut self, _gl: Option<&eframe::glow::Context>) {
         let _ = self.discord_client.close();
+        //measurements since the last finished album would be lost otherwise, the app won't wait for a thread
+        if self.analysis_job.is_some() && self.scan_job.is_none() {
+            if let Some(path) = cache::cache_path(){
+                cache::save(&path, self.song_info.iter().map(|(_, song)| song.clone()).collect());
+            }
+        }
     }
 }
\ No newline at end of file
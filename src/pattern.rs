@@ -0,0 +1,233 @@
+use std::path::{Component, Path};
+
+/// Fields a filename pattern can fill in, written as `%name%` inside a pattern.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Field {
+    Artist,
+    AlbumArtist,
+    Album,
+    Title,
+    Track,
+    Disc,
+    Year,
+    Genre,
+    Ignore,     //%_% matches text that shouldn't end up anywhere
+}
+
+impl Field {
+    fn from_name(name: &str) -> Option<Field> {
+        match name {
+            "artist" => Some(Field::Artist),
+            "albumartist" => Some(Field::AlbumArtist),
+            "album" => Some(Field::Album),
+            "title" => Some(Field::Title),
+            "track" => Some(Field::Track),
+            "disc" => Some(Field::Disc),
+            "year" => Some(Field::Year),
+            "genre" => Some(Field::Genre),
+            "_" => Some(Field::Ignore),
+            _ => None,
+        }
+    }
+
+    fn is_numeric(&self) -> bool {
+        matches!(self, Field::Track | Field::Disc | Field::Year)
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Segment {
+    Literal(String),
+    Field(Field),
+}
+
+/// Tags pulled out of a file's path by a `TagPattern`.
+#[derive(Clone, Default, Debug)]
+pub struct InferredTags {
+    pub artist: Option<String>,
+    pub album_artist: Option<String>,
+    pub album: Option<String>,
+    pub title: Option<String>,
+    pub track: Option<usize>,
+    pub disc: Option<usize>,
+    pub year: Option<u32>,
+    pub genre: Option<String>,
+}
+
+/// A pattern like `%artist%/%album%/%track% - %title%` for guessing tags of untagged files.
+/// It's matched against the end of the file's path relative to its library root, without the
+/// extension, so a pattern with one `/` looks at the file name and the folder it's in.
+#[derive(Clone, Debug)]
+pub struct TagPattern {
+    segments: Vec<Segment>,
+    depth: usize,   //number of path components the pattern spans
+}
+
+impl TagPattern {
+    pub fn parse(pattern: &str) -> Result<TagPattern, String> {
+        let mut segments = Vec::new();
+        let mut rest = pattern;
+        while let Some(start) = rest.find('%') {
+            if start > 0 {
+                segments.push(Segment::Literal(rest[..start].to_string()));
+            }
+            let after = &rest[start + 1..];
+            let end = after.find('%').ok_or_else(|| "unclosed %".to_string())?;
+            let name = &after[..end];
+            let field = Field::from_name(&name.to_lowercase()).ok_or_else(|| format!("unknown field %{}%", name))?;
+            if matches!(segments.last(), Some(Segment::Field(_))) {
+                return Err("two fields need some text between them".to_string());
+            }
+            segments.push(Segment::Field(field));
+            rest = &after[end + 1..];
+        }
+        if !rest.is_empty() {
+            segments.push(Segment::Literal(rest.to_string()));
+        }
+        if !segments.iter().any(|s| matches!(s, Segment::Field(_))) {
+            return Err("pattern has no fields".to_string());
+        }
+        Ok(TagPattern {
+            depth: pattern.matches('/').count() + 1,
+            segments,
+        })
+    }
+
+    /// Matches the pattern against a path relative to the library root, returns None if it doesn't fit.
+    pub fn apply(&self, relative: &Path) -> Option<InferredTags> {
+        let components: Vec<String> = relative.with_extension("").components()
+            .filter_map(|c| match c {
+                Component::Normal(part) => Some(part.to_string_lossy().to_string()),
+                _ => None,
+            })
+            .collect();
+        if components.len() < self.depth {
+            return None;
+        }
+        let text = components[components.len() - self.depth..].join("/");
+
+        let mut captures = Vec::new();
+        if !match_segments(&self.segments, &text, &mut captures) {
+            return None;
+        }
+        let mut tags = InferredTags::default();
+        for (field, value) in captures {
+            let value = value.trim().to_string();
+            match field {
+                Field::Artist => tags.artist = Some(value),
+                Field::AlbumArtist => tags.album_artist = Some(value),
+                Field::Album => tags.album = Some(value),
+                Field::Title => tags.title = Some(value),
+                Field::Track => tags.track = value.parse().ok(),
+                Field::Disc => tags.disc = value.parse().ok(),
+                Field::Year => tags.year = value.parse().ok(),
+                Field::Genre => tags.genre = Some(value),
+                Field::Ignore => {}
+            }
+        }
+        Some(tags)
+    }
+}
+
+//Backtracking match, a field takes the shortest text that lets the rest of the pattern match.
+//Fields never cross a `/` and numeric fields only take digits.
+fn match_segments<'a>(segments: &[Segment], text: &'a str, captures: &mut Vec<(Field, &'a str)>) -> bool {
+    let Some((first, rest)) = segments.split_first() else {
+        return text.is_empty();
+    };
+    match first {
+        Segment::Literal(literal) => text.strip_prefix(literal.as_str()).is_some_and(|remaining| match_segments(rest, remaining, captures)),
+        Segment::Field(field) => {
+            for (end, c) in text.char_indices().map(|(i, c)| (i + c.len_utf8(), c)) {
+                if c == '/' || (field.is_numeric() && !c.is_ascii_digit()) {
+                    break;
+                }
+                if !text[..end].trim().is_empty() {
+                    captures.push((*field, &text[..end]));
+                    if match_segments(rest, &text[end..], captures) {
+                        return true;
+                    }
+                    captures.pop();
+                }
+            }
+            false
+        }
+    }
+}
+
+/// Parses the patterns from the settings, ones that don't parse are left out.
+pub fn compile(patterns: &[String]) -> Vec<TagPattern> {
+    patterns.iter().filter_map(|p| TagPattern::parse(p).ok()).collect()
+}
+
+/// Tries each pattern in order and returns the tags of the first one that matched.
+pub fn infer_tags(patterns: &[TagPattern], relative: &Path) -> Option<InferredTags> {
+    patterns.iter().find_map(|pattern| pattern.apply(relative))
+}
+
+pub fn default_patterns() -> Vec<String> {
+    vec![
+        "%artist%/%album%/%track% - %title%".to_string(),
+        "%artist% - %title%".to_string(),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(pattern: &str, path: &str) -> Option<InferredTags> {
+        TagPattern::parse(pattern).unwrap().apply(Path::new(path))
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(TagPattern::parse("%artist").is_err());
+        assert!(TagPattern::parse("%nope%").is_err());
+        assert!(TagPattern::parse("%artist%%title%").is_err());
+        assert!(TagPattern::parse("just text").is_err());
+        assert!(TagPattern::parse("%Artist% - %TITLE%").is_ok());
+    }
+
+    #[test]
+    fn matches_the_end_of_the_path() {
+        let tags = apply("%artist%/%album%/%track% - %title%", "Music/Miles Davis/Kind of Blue/01 - So What.flac").unwrap();
+        assert_eq!(tags.artist.as_deref(), Some("Miles Davis"));
+        assert_eq!(tags.album.as_deref(), Some("Kind of Blue"));
+        assert_eq!(tags.track, Some(1));
+        assert_eq!(tags.title.as_deref(), Some("So What"));
+        assert!(apply("%artist%/%album%/%track% - %title%", "Kind of Blue/01 - So What.flac").is_none());
+    }
+
+    #[test]
+    fn backtracks_past_separators_inside_fields() {
+        //"Jay" is the shortest artist, but "Z" isn't a track number, so the match has to back up and try again
+        let tags = apply("%artist% - %track% - %title%", "Jay - Z - 03 - Song - Remix.mp3").unwrap();
+        assert_eq!(tags.artist.as_deref(), Some("Jay - Z"));
+        assert_eq!(tags.track, Some(3));
+        assert_eq!(tags.title.as_deref(), Some("Song - Remix"));
+    }
+
+    #[test]
+    fn fields_stay_inside_one_path_component() {
+        assert!(apply("%artist% - %title%", "A/B - C.mp3").is_some());
+        assert!(apply("%artist% - %title%", "A - B/C.mp3").is_none());
+    }
+
+    #[test]
+    fn numeric_fields_only_take_digits() {
+        assert!(apply("%track% %title%", "Intro Outro.mp3").is_none());
+        let tags = apply("%year% %_% %title%", "1999 skipped Title.mp3").unwrap();
+        assert_eq!(tags.year, Some(1999));
+        assert_eq!(tags.title.as_deref(), Some("Title"));
+    }
+
+    #[test]
+    fn first_matching_pattern_wins() {
+        let patterns = compile(&default_patterns());
+        let tags = infer_tags(&patterns, Path::new("Artist - Title.ogg")).unwrap();
+        assert_eq!(tags.artist.as_deref(), Some("Artist"));
+        assert_eq!(tags.album, None);
+        assert!(infer_tags(&patterns, Path::new("Title.ogg")).is_none());
+    }
+}
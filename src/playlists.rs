@@ -0,0 +1,166 @@
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use crate::smart::SmartPlaylist;
+
+/// A song in a playlist. Songs are kept by path, ids only last as long as the app runs.
+#[derive(Clone, Hash, Serialize, Deserialize)]
+pub struct PlaylistEntry {
+    pub path: PathBuf,
+    #[serde(skip)]
+    pub uid: usize,     //for egui_dnd, a playlist can hold the same song twice
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Playlist {
+    pub id: u64,
+    pub name: String,
+    pub songs: Vec<PlaylistEntry>,
+}
+
+impl std::hash::Hash for Playlist {
+    //egui_dnd tells the playlists apart by hash, the id alone keeps that stable while one is renamed or edited
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
+    }
+}
+
+/// Every saved playlist, in the order the sidebar lists them. Smart playlists share the ids, so an id
+/// is enough to tell which playlist is meant.
+#[derive(Default, Serialize, Deserialize)]
+pub struct Playlists {
+    pub lists: Vec<Playlist>,
+    #[serde(default)]
+    pub smart: Vec<SmartPlaylist>,
+    next_id: u64,
+    #[serde(skip)]
+    next_uid: usize,
+}
+
+impl Playlists {
+    /// Reads the playlists as `save` wrote them, with fresh entry uids.
+    pub fn load(text: &str) -> Result<Playlists, String> {
+        let mut playlists: Playlists = ron::from_str(text).map_err(|e| e.to_string())?;
+        let mut uid = 0;
+        for entry in playlists.lists.iter_mut().flat_map(|p| p.songs.iter_mut()) {
+            entry.uid = uid;
+            uid += 1;
+        }
+        playlists.next_uid = uid;
+        Ok(playlists)
+    }
+
+    pub fn save(&self) -> Result<String, String> {
+        ron::to_string(self).map_err(|e| e.to_string())
+    }
+
+    /// Adds an empty playlist at the end and returns its id. Taken names get a number added.
+    pub fn create(&mut self, name: &str) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        let name = self.unique_name(name, None);
+        self.lists.push(Playlist { id, name, songs: Vec::new() });
+        id
+    }
+
+    /// Adds a smart playlist at the end under a new id, which is returned.
+    pub fn add_smart(&mut self, mut playlist: SmartPlaylist) -> u64 {
+        playlist.id = self.next_id;
+        self.next_id += 1;
+        playlist.name = self.unique_name(&playlist.name, None);
+        let id = playlist.id;
+        self.smart.push(playlist);
+        id
+    }
+
+    /// Replaces the smart playlist with the same id, e.g. after its rules were edited.
+    pub fn update_smart(&mut self, mut playlist: SmartPlaylist) {
+        let name = match playlist.name.trim() {
+            "" => self.name(playlist.id).unwrap_or("Smart Playlist").to_string(),
+            name => name.to_string(),
+        };
+        playlist.name = self.unique_name(&name, Some(playlist.id));
+        if let Some(existing) = self.smart.iter_mut().find(|p| p.id == playlist.id) {
+            *existing = playlist;
+        }
+    }
+
+    pub fn get_smart(&self, id: u64) -> Option<&SmartPlaylist> {
+        self.smart.iter().find(|p| p.id == id)
+    }
+
+    /// Copies a playlist to right after itself, returns the copy's id.
+    pub fn duplicate(&mut self, id: u64) -> Option<u64> {
+        if let Some(index) = self.smart.iter().position(|p| p.id == id) {
+            let mut copy = self.smart[index].clone();
+            copy.id = self.next_id;
+            self.next_id += 1;
+            copy.name = self.unique_name(&format!("{} (copy)", copy.name), None);
+            let copy_id = copy.id;
+            self.smart.insert(index + 1, copy);
+            return Some(copy_id);
+        }
+        let index = self.lists.iter().position(|p| p.id == id)?;
+        let original = self.lists[index].clone();
+        let copy_id = self.next_id;
+        self.next_id += 1;
+        let name = self.unique_name(&format!("{} (copy)", original.name), None);
+        let songs = original.songs.iter().map(|e| self.new_entry(&e.path)).collect();
+        self.lists.insert(index + 1, Playlist { id: copy_id, name, songs });
+        Some(copy_id)
+    }
+
+    pub fn delete(&mut self, id: u64) {
+        self.lists.retain(|p| p.id != id);
+        self.smart.retain(|p| p.id != id);
+    }
+
+    pub fn rename(&mut self, id: u64, name: &str) {
+        let name = name.trim();
+        if name.is_empty() {
+            return;
+        }
+        let name = self.unique_name(name, Some(id));
+        if let Some(playlist) = self.get_mut(id) {
+            playlist.name = name;
+        }
+    }
+
+    /// Name of a playlist of either kind.
+    pub fn name(&self, id: u64) -> Option<&str> {
+        self.get(id).map(|p| p.name.as_str()).or(self.get_smart(id).map(|p| p.name.as_str()))
+    }
+
+    pub fn get(&self, id: u64) -> Option<&Playlist> {
+        self.lists.iter().find(|p| p.id == id)
+    }
+
+    pub fn get_mut(&mut self, id: u64) -> Option<&mut Playlist> {
+        self.lists.iter_mut().find(|p| p.id == id)
+    }
+
+    /// Appends songs to the end of a playlist.
+    pub fn add(&mut self, id: u64, paths: &[PathBuf]) {
+        let entries: Vec<PlaylistEntry> = paths.iter().map(|path| self.new_entry(path)).collect();
+        if let Some(playlist) = self.get_mut(id) {
+            playlist.songs.extend(entries);
+        }
+    }
+
+    fn new_entry(&mut self, path: &Path) -> PlaylistEntry {
+        let entry = PlaylistEntry { path: path.to_path_buf(), uid: self.next_uid };
+        self.next_uid += 1;
+        entry
+    }
+
+    //`name`, or with a number added when another playlist than `except` has it already
+    fn unique_name(&self, name: &str, except: Option<u64>) -> String {
+        let taken = |candidate: &str| {
+            self.lists.iter().any(|p| p.name == candidate && Some(p.id) != except)
+                || self.smart.iter().any(|p| p.name == candidate && Some(p.id) != except)
+        };
+        if !taken(name) {
+            return name.to_string();
+        }
+        (2..).map(|n| format!("{} {}", name, n)).find(|candidate| !taken(candidate)).unwrap()
+    }
+}
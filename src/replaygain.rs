@@ -0,0 +1,199 @@
+use std::path::Path;
+use lofty::{config::WriteOptions, file::TaggedFileExt, probe::Probe, tag::{ItemKey, Tag, TagExt}};
+use serde::{Deserialize, Serialize};
+
+/// R128 gains are relative to -23 LUFS, ReplayGain 2.0 aims for -18, so they're shifted to match.
+const R128_TO_REPLAYGAIN_DB: f32 = 5.0;
+
+/// Gains are kept within this many dB either way, anything beyond is a broken tag or measurement.
+const MAX_GAIN_DB: f32 = 24.0;
+
+/// Loudness normalisation values of a song, gains in dB and peaks as linear sample values (1.0 is full scale).
+#[derive(Clone, Copy, Default, PartialEq, Debug, Serialize, Deserialize)]
+pub struct ReplayGain {
+    pub track_gain: Option<f32>,
+    pub track_peak: Option<f32>,
+    pub album_gain: Option<f32>,
+    pub album_peak: Option<f32>,
+}
+
+impl ReplayGain {
+    /// Reads the REPLAYGAIN_* values of a tag, or the R128_* ones Opus files use instead.
+    pub fn from_tag(tag: &Tag) -> ReplayGain {
+        let mut gain = ReplayGain {
+            track_gain: tag.get_string(&ItemKey::ReplayGainTrackGain).and_then(parse_db),
+            track_peak: tag.get_string(&ItemKey::ReplayGainTrackPeak).and_then(|p| p.trim().parse().ok()),
+            album_gain: tag.get_string(&ItemKey::ReplayGainAlbumGain).and_then(parse_db),
+            album_peak: tag.get_string(&ItemKey::ReplayGainAlbumPeak).and_then(|p| p.trim().parse().ok()),
+        };
+        if gain.track_gain.is_none() {
+            gain.track_gain = unknown_item(tag, "R128_TRACK_GAIN").and_then(parse_r128);
+        }
+        if gain.album_gain.is_none() {
+            gain.album_gain = unknown_item(tag, "R128_ALBUM_GAIN").and_then(parse_r128);
+        }
+        gain
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.track_gain.is_none() && self.album_gain.is_none()
+    }
+
+    /// Linear factor to play the song at. A missing album gain falls back to the track gain and the other
+    /// way around, a song without either plays unchanged.
+    pub fn factor(&self, mode: GainMode, queued_as_album: bool, prevent_clipping: bool) -> f32 {
+        let use_album = match mode {
+            GainMode::Off => return 1.0,
+            GainMode::Track => false,
+            GainMode::Album => true,
+            GainMode::Auto => queued_as_album,
+        };
+        let (gain, peak) = if use_album {
+            (self.album_gain.or(self.track_gain), self.album_peak.or(self.track_peak))
+        } else {
+            (self.track_gain.or(self.album_gain), self.track_peak.or(self.album_peak))
+        };
+        let Some(gain) = gain.filter(|g| g.is_finite()) else {
+            return 1.0;
+        };
+        let factor = 10f32.powf(gain.clamp(-MAX_GAIN_DB, MAX_GAIN_DB) / 20.0);
+        match peak.filter(|p| *p > 0.0) {
+            //don't boost the loudest sample past full scale
+            Some(peak) if prevent_clipping => factor.min(1.0 / peak),
+            _ => factor,
+        }
+    }
+
+    /// Short description for the song details, e.g. "track -6.54 dB, album -7.10 dB".
+    pub fn summary(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some(gain) = self.track_gain {
+            parts.push(format!("track {:+.2} dB", gain));
+        }
+        if let Some(gain) = self.album_gain {
+            parts.push(format!("album {:+.2} dB", gain));
+        }
+        parts.join(", ")
+    }
+}
+
+/// Writes the values into the file's primary tag as REPLAYGAIN_* items, adding a tag if the file has none.
+pub fn write_tags(path: &Path, gain: &ReplayGain) -> Result<(), String> {
+    let mut tagged_file = Probe::open(path).and_then(|p| p.read()).map_err(|e| e.to_string())?;
+    if tagged_file.primary_tag().is_none() {
+        let tag_type = tagged_file.primary_tag_type();
+        tagged_file.insert_tag(Tag::new(tag_type));
+    }
+    let Some(tag) = tagged_file.primary_tag_mut() else {
+        return Err("file type can't hold tags".to_string());
+    };
+    let items = [
+        (ItemKey::ReplayGainTrackGain, gain.track_gain.map(|g| format!("{:.2} dB", g))),
+        (ItemKey::ReplayGainTrackPeak, gain.track_peak.map(|p| format!("{:.6}", p))),
+        (ItemKey::ReplayGainAlbumGain, gain.album_gain.map(|g| format!("{:.2} dB", g))),
+        (ItemKey::ReplayGainAlbumPeak, gain.album_peak.map(|p| format!("{:.6}", p))),
+    ];
+    for (key, value) in items {
+        if let Some(value) = value {
+            tag.insert_text(key, value);
+        }
+    }
+    tag.save_to_path(path, WriteOptions::default()).map_err(|e| e.to_string())
+}
+
+//"-6.54 dB", the unit is optional
+fn parse_db(value: &str) -> Option<f32> {
+    let value = value.trim();
+    let number = value.strip_suffix("dB").or(value.strip_suffix("db")).unwrap_or(value);
+    number.trim().parse().ok().filter(|g: &f32| g.is_finite())
+}
+
+//Q7.8 fixed point, 256 is 1 dB
+fn parse_r128(value: &str) -> Option<f32> {
+    let raw: i32 = value.trim().parse().ok()?;
+    Some(raw as f32 / 256.0 + R128_TO_REPLAYGAIN_DB)
+}
+
+fn unknown_item<'a>(tag: &'a Tag, key: &str) -> Option<&'a str> {
+    tag.items()
+        .find(|item| matches!(item.key(), ItemKey::Unknown(k) if k.eq_ignore_ascii_case(key)))
+        .and_then(|item| item.value().text())
+}
+
+/// Which ReplayGain value playback normalises to.
+#[derive(Clone, Copy, PartialEq, Default)]
+pub enum GainMode {
+    Off,
+    Track,
+    Album,
+    #[default]
+    Auto,       //album gain for albums queued as a whole, track gain for everything else
+}
+
+impl GainMode {
+    pub const ALL: [GainMode; 4] = [GainMode::Off, GainMode::Track, GainMode::Album, GainMode::Auto];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            GainMode::Off => "Off",
+            GainMode::Track => "Track",
+            GainMode::Album => "Album",
+            GainMode::Auto => "Auto",
+        }
+    }
+
+    pub fn from_label(label: &str) -> Option<GainMode> {
+        GainMode::ALL.into_iter().find(|m| m.label() == label)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(gain: f32, peak: Option<f32>) -> ReplayGain {
+        ReplayGain { track_gain: Some(gain), track_peak: peak, ..Default::default() }
+    }
+
+    #[test]
+    fn non_finite_gain_plays_unchanged() {
+        assert_eq!(track(f32::INFINITY, Some(0.0)).factor(GainMode::Track, false, true), 1.0);
+        assert_eq!(track(f32::NAN, None).factor(GainMode::Track, false, false), 1.0);
+    }
+
+    #[test]
+    fn gain_is_clamped() {
+        let factor = track(200.0, None).factor(GainMode::Track, false, false);
+        assert!((factor - 10f32.powf(MAX_GAIN_DB / 20.0)).abs() < 1e-3);
+    }
+
+    #[test]
+    fn clipping_prevention_caps_at_peak() {
+        let factor = track(6.0, Some(0.8)).factor(GainMode::Track, false, true);
+        assert!((factor - 1.25).abs() < 1e-6);
+    }
+
+    #[test]
+    fn album_falls_back_to_track_gain() {
+        let gain = ReplayGain { track_gain: Some(-6.0), album_gain: None, ..Default::default() };
+        assert_eq!(gain.factor(GainMode::Album, false, false), gain.factor(GainMode::Track, false, false));
+        assert_eq!(gain.factor(GainMode::Off, true, false), 1.0);
+    }
+
+    #[test]
+    fn from_tag_reads_replaygain_and_r128() {
+        let mut tag = Tag::new(lofty::tag::TagType::VorbisComments);
+        tag.insert_text(ItemKey::ReplayGainTrackGain, "-6.54 dB".to_string());
+        tag.insert_text(ItemKey::ReplayGainTrackPeak, "0.987654".to_string());
+        tag.insert_text(ItemKey::ReplayGainAlbumGain, "inf dB".to_string());
+        let gain = ReplayGain::from_tag(&tag);
+        assert_eq!(gain.track_gain, Some(-6.54));
+        assert_eq!(gain.track_peak, Some(0.987654));
+        assert_eq!(gain.album_gain, None);
+
+        //as an Opus file's comments come out of lofty
+        let mut comments = lofty::ogg::VorbisComments::new();
+        comments.push("R128_TRACK_GAIN".to_string(), "-512".to_string());
+        assert_eq!(ReplayGain::from_tag(&Tag::from(comments)).track_gain, Some(3.0));
+    }
+}
@@ -0,0 +1,551 @@
+use std::{collections::HashSet, fs, path::{Component, Path, PathBuf}, sync::{atomic::{AtomicBool, Ordering}, mpsc::{self, Receiver}, Arc}, thread, time::SystemTime};
+use lofty::{aac::AacFile, config::ParseOptions, file::{AudioFile, FileType, TaggedFileExt}, iff::{aiff::AiffFile, wav::WavFile}, mpeg::MpegFile, probe::Probe, tag::{self, Accessor}};
+use lofty::id3::v2::{Frame, FrameFlags, PopularimeterFrame};
+use crate::{cache, library::SongInfo, pattern::{self, TagPattern}, replaygain::ReplayGain};
+
+/// Name of the per-directory ignore file. Each non-empty line that isn't a `#` comment
+/// is a name pattern (`*` and `?` wildcards), a trailing `/` restricts it to directories.
+/// Rules apply to the directory the file sits in and everything below it.
+pub const IGNORE_FILE_NAME: &str = ".coralignore";
+
+#[derive(Clone)]
+pub struct ScanOptions {
+    pub max_depth: usize,   //how many folder levels below a library root are entered, 0 = root only
+    pub skip_hidden: bool,  //skip dot files and folders
+    pub tag_patterns: Vec<String>,  //filename patterns for untagged files, tried in order, see pattern.rs
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        Self {
+            max_depth: 8,
+            skip_hidden: true,
+            tag_patterns: pattern::default_patterns(),
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+struct IgnoreRules {
+    patterns: Vec<(String, bool)>, //pattern, directories only
+}
+
+impl IgnoreRules {
+    fn extended_from(&self, dir: &Path) -> IgnoreRules {
+        let mut rules = self.clone();
+        if let Ok(contents) = fs::read_to_string(dir.join(IGNORE_FILE_NAME)) {
+            for line in contents.lines().map(|l| l.trim()) {
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                match line.strip_suffix('/') {
+                    Some(dir_pattern) => rules.patterns.push((dir_pattern.to_string(), true)),
+                    None => rules.patterns.push((line.to_string(), false)),
+                }
+            }
+        }
+        rules
+    }
+
+    fn is_ignored(&self, name: &str, is_dir: bool) -> bool {
+        self.patterns.iter().any(|(pattern, dirs_only)| (is_dir || !dirs_only) && wildcard_match(pattern, name))
+    }
+}
+
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = backtrack {
+            //let the last star swallow one more character and retry
+            p = star_p + 1;
+            n = star_n + 1;
+            backtrack = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Returns every file below `root`, sorted per directory, honouring the depth limit,
+/// hidden entries and `.coralignore` rules.
+pub fn collect_files(root: &Path, options: &ScanOptions) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    let mut visited = HashSet::new();
+    walk(root, 0, options, &IgnoreRules::default(), &mut visited, &mut files);
+    files
+}
+
+/// Files to scan for `root`, a library root or a file or folder inside one that the watcher reported. Anything
+/// inside a root gets the same depth limit, hidden check and `.coralignore` rules as if the whole root was walked.
+fn collect_root(root: &Path, library_dirs: &[PathBuf], options: &ScanOptions) -> Vec<PathBuf> {
+    let Some(library_dir) = library_dirs.iter().filter(|dir| root.starts_with(dir)).max_by_key(|dir| dir.components().count()) else {
+        return if root.is_file() { vec![root.to_path_buf()] } else { collect_files(root, options) };
+    };
+    let relative: Vec<Component> = root.strip_prefix(library_dir).unwrap_or(root).components().collect();
+    //check every folder on the way down like walk would have
+    let mut rules = IgnoreRules::default();
+    let mut dir = library_dir.clone();
+    for (depth, component) in relative.iter().enumerate() {
+        rules = rules.extended_from(&dir);
+        let name = component.as_os_str().to_string_lossy().to_string();
+        dir.push(component);
+        let is_dir = dir.is_dir();
+        if name == IGNORE_FILE_NAME || (options.skip_hidden && name.starts_with('.')) || rules.is_ignored(&name, is_dir) {
+            return Vec::new();
+        }
+        if is_dir && depth >= options.max_depth {
+            return Vec::new();
+        }
+    }
+    if root.is_file() {
+        return vec![root.to_path_buf()];
+    }
+    let mut files = Vec::new();
+    walk(root, relative.len(), options, &rules, &mut HashSet::new(), &mut files);
+    files
+}
+
+fn walk(dir: &Path, depth: usize, options: &ScanOptions, inherited: &IgnoreRules, visited: &mut HashSet<PathBuf>, files: &mut Vec<PathBuf>) {
+    //canonical paths catch symlink loops as well as the same folder being reached twice
+    let Ok(canonical) = fs::canonicalize(dir) else {
+        return;
+    };
+    if !visited.insert(canonical) {
+        return;
+    }
+
+    let rules = inherited.extended_from(dir);
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    let mut entries: Vec<_> = entries.flatten().collect();
+    entries.sort_by_key(|e| e.file_name());
+
+    for entry in entries {
+        let path = entry.path();
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name == IGNORE_FILE_NAME || (options.skip_hidden && name.starts_with('.')) {
+            continue;
+        }
+        //fs::metadata follows symlinks so linked album folders get scanned like real ones
+        let Ok(metadata) = fs::metadata(&path) else {
+            continue;
+        };
+        if rules.is_ignored(&name, metadata.is_dir()) {
+            continue;
+        }
+        if metadata.is_dir() {
+            if depth < options.max_depth {
+                walk(&path, depth + 1, options, &rules, visited, files);
+            }
+        } else if metadata.is_file() {
+            files.push(path);
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ScanErrorKind {
+    Unreadable,     //couldn't open or read the file at all
+    Unsupported,    //not a format lofty recognises, cover art, cue sheets, text files...
+    Corrupt,        //looks like audio but the tags or headers are broken
+    Untagged,       //read fine but had no tags, the song was added with names guessed from the file
+}
+
+impl ScanErrorKind {
+    pub fn label(&self) -> &'static str {
+        match self {
+            ScanErrorKind::Unreadable => "Unreadable",
+            ScanErrorKind::Unsupported => "Unsupported",
+            ScanErrorKind::Corrupt => "Corrupt",
+            ScanErrorKind::Untagged => "Untagged",
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ScanError {
+    pub path: PathBuf,
+    pub kind: ScanErrorKind,
+    pub reason: String,
+}
+
+pub enum ScanMessage {
+    Started { total: usize },
+    Progress { done: usize, path: PathBuf },
+    Song(Box<SongInfo>),
+    Error(ScanError),
+    Finished,
+}
+
+/// Handle to a scan running on a worker thread, polled by the UI every frame.
+pub struct ScanJob {
+    receiver: Receiver<ScanMessage>,
+    cancel: Arc<AtomicBool>,
+    pub total: usize,
+    pub done: usize,
+    pub current: PathBuf,
+}
+
+impl ScanJob {
+    /// Walks and probes `roots` in the background, results are streamed back through `poll`.
+    /// Files whose size and modification time match their entry in `cache_path` aren't probed again.
+    /// `library_dirs` are the library roots, filename patterns are matched relative to them.
+    pub fn start(roots: Vec<PathBuf>, library_dirs: Vec<PathBuf>, options: ScanOptions, cache_path: Option<PathBuf>, ctx: egui::Context) -> ScanJob {
+        let (sender, receiver) = mpsc::channel();
+        let cancel = Arc::new(AtomicBool::new(false));
+        let worker_cancel = cancel.clone();
+
+        thread::spawn(move || {
+            let cached = cache_path.map(|p| cache::load(&p)).unwrap_or_default();
+            let patterns = pattern::compile(&options.tag_patterns);
+            let files: Vec<PathBuf> = roots.iter().flat_map(|root| collect_root(root, &library_dirs, &options)).collect();
+            if sender.send(ScanMessage::Started { total: files.len() }).is_err() {
+                return;
+            }
+            for (i, path) in files.into_iter().enumerate() {
+                if worker_cancel.load(Ordering::Relaxed) {
+                    break;
+                }
+                let stamp = cache::file_stamp(&path);
+                let relative = relative_to_library(&path, &library_dirs);
+                let mut messages = Vec::new();
+                match cached.get(&path) {
+                    Some(song) if stamp == Some((song.file_size, song.modified)) => {
+                        let mut song = song.clone();
+                        let mut error = None;
+                        if song.tags_inferred {
+                            //the patterns may have changed since this was cached, guessing again is cheap
+                            let matched = apply_inferred_tags(&mut song, &relative, &patterns);
+                            //the scan cleared this root's reports, so it's reported again
+                            error = Some(untagged_error(&path, &song, matched));
+                        }
+                        messages.push(ScanMessage::Song(Box::new(song)));
+                        messages.extend(error.map(ScanMessage::Error));
+                    }
+                    _ => {
+                        let (song, error) = read_song_info(&path, &relative, &patterns);
+                        if let Some(mut song) = song {
+                            if let Some((size, modified)) = stamp {
+                                song.file_size = size;
+                                song.modified = modified;
+                            }
+                            //a rewritten file is still the song that was added back then, and retagging it,
+                            //like writing the measured ReplayGain, doesn't change how loud it is
+                            match cached.get(&path) {
+                                Some(cached) => {
+                                    song.added = cached.added;
+                                    song.loudness = cached.loudness;
+                                }
+                                None => song.added = added_time(&path).unwrap_or(song.modified),
+                            }
+                            messages.push(ScanMessage::Song(Box::new(song)));
+                        }
+                        if let Some(error) = error {
+                            messages.push(ScanMessage::Error(error));
+                        }
+                    }
+                }
+                for message in messages {
+                    if sender.send(message).is_err() {
+                        return; //job was dropped, nobody is listening anymore
+                    }
+                }
+                let _ = sender.send(ScanMessage::Progress { done: i + 1, path });
+                ctx.request_repaint();
+            }
+            let _ = sender.send(ScanMessage::Finished);
+            ctx.request_repaint();
+        });
+
+        ScanJob {
+            receiver,
+            cancel,
+            total: 0,
+            done: 0,
+            current: PathBuf::new(),
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.cancel.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.load(Ordering::Relaxed)
+    }
+
+    /// Drains everything the worker sent since the last call, keeping the progress counters up to date.
+    pub fn poll(&mut self) -> Vec<ScanMessage> {
+        let mut messages = Vec::new();
+        while let Ok(message) = self.receiver.try_recv() {
+            match &message {
+                ScanMessage::Started { total } => self.total = *total,
+                ScanMessage::Progress { done, path } => {
+                    self.done = *done;
+                    self.current = path.clone();
+                }
+                _ => {}
+            }
+            messages.push(message);
+        }
+        messages
+    }
+}
+
+//Songs new to the cache count as added when the file was created, which is when it was copied into the
+//library folder, so a fresh cache doesn't make the whole library look added today.
+fn added_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).ok()?.created().ok()
+}
+
+/// Path of `path` below the library root containing it, just the file name if it isn't under one.
+fn relative_to_library(path: &Path, library_dirs: &[PathBuf]) -> PathBuf {
+    library_dirs.iter()
+        .filter_map(|dir| path.strip_prefix(dir).ok())
+        .min_by_key(|relative| relative.components().count())
+        .or(path.file_name().map(Path::new))
+        .unwrap_or(path)
+        .to_path_buf()
+}
+
+/// Fills in the names of an untagged song from the first filename pattern that fits its path.
+/// Falls back to the bare file name as the title, returns whether a pattern matched.
+fn apply_inferred_tags(song: &mut SongInfo, relative: &Path, patterns: &[TagPattern]) -> bool {
+    let inferred = pattern::infer_tags(patterns, relative);
+    let tags = inferred.clone().unwrap_or_default();
+    let file_name = relative.file_stem().map(|os| os.to_string_lossy().to_string()).unwrap_or_default();
+
+    song.tags_inferred = true;
+    song.artist = tags.artist.unwrap_or("Unknown Artist".to_string());
+    song.track = tags.title.unwrap_or(file_name);
+    song.album = tags.album.unwrap_or_default();
+    song.album_artist = tags.album_artist;
+    song.track_number = tags.track;
+    song.disc_number = tags.disc;
+    song.year = tags.year;
+    song.genre = tags.genre;
+    inferred.is_some()
+}
+
+//Report for a song that had no tags, the same whether it was just probed or came from the cache.
+fn untagged_error(path: &Path, song: &SongInfo, pattern_matched: bool) -> ScanError {
+    let reason = if pattern_matched {
+        format!("no tags, guessed \"{}\" by \"{}\" from the path", song.track, song.artist)
+    } else {
+        "no tags and no filename pattern matched".to_string()
+    };
+    ScanError { path: path.to_path_buf(), kind: ScanErrorKind::Untagged, reason }
+}
+
+/// Probes a single file. Anything that goes wrong is reported instead of aborting the scan, a song can
+/// still come back alongside a report when it was only partially readable.
+fn read_song_info(path: &Path, relative: &Path, patterns: &[TagPattern]) -> (Option<SongInfo>, Option<ScanError>) {
+    let report = |kind: ScanErrorKind, reason: String| Some(ScanError { path: path.to_path_buf(), kind, reason });
+
+    let probe = match Probe::open(path).and_then(|p| p.guess_file_type().map_err(Into::into)) {
+        Ok(probe) => probe,
+        Err(e) => return (None, report(ScanErrorKind::Unreadable, e.to_string())),
+    };
+    if probe.file_type().is_none() {
+        return (None, report(ScanErrorKind::Unsupported, "not a recognised audio file".to_string()));
+    }
+    let tagged_file = match probe.read() {
+        Ok(file) => file,
+        Err(e) => return (None, report(ScanErrorKind::Corrupt, e.to_string())),
+    };
+
+    let mut new_song = SongInfo::default();
+    let mut error = None;
+    if let Some(prim_tag) = tagged_file.primary_tag() {
+        new_song.artist = prim_tag.get_string(&tag::ItemKey::TrackArtist).unwrap_or("Unknown Artist").to_string();
+        new_song.track = prim_tag.get_string(&tag::ItemKey::TrackTitle).unwrap_or("Unknown Title").to_string();
+        new_song.album = prim_tag.get_string(&tag::ItemKey::AlbumTitle).unwrap_or("Unknown Album").to_string();
+        new_song.track_number = prim_tag.track().map(|n| n as usize);
+        new_song.track_total = prim_tag.track_total().map(|n| n as usize);
+        new_song.album_artist = prim_tag.get_string(&tag::ItemKey::AlbumArtist).map(|s| s.to_string());
+        new_song.disc_number = prim_tag.disk().map(|n| n as usize);
+        new_song.disc_total = prim_tag.disk_total().map(|n| n as usize);
+        new_song.year = prim_tag.year();
+        new_song.date = prim_tag.get_string(&tag::ItemKey::RecordingDate).map(|s| s.to_string());
+        new_song.genre = prim_tag.genre().map(|s| s.to_string());
+        new_song.composer = prim_tag.get_string(&tag::ItemKey::Composer).map(|s| s.to_string());
+        new_song.comment = prim_tag.comment().map(|s| s.to_string());
+        new_song.replay_gain = ReplayGain::from_tag(prim_tag);
+        new_song.rating = read_rating(prim_tag, path, tagged_file.file_type());
+    } else {
+        let matched = apply_inferred_tags(&mut new_song, relative, patterns);
+        error = Some(untagged_error(path, &new_song, matched));
+    }
+    new_song.path = path.to_path_buf();
+    //the header's duration is good enough and saves decoding every file just to measure it
+    let properties = tagged_file.properties();
+    new_song.duration = properties.duration();
+    new_song.bitrate = properties.audio_bitrate().or(properties.overall_bitrate());
+    new_song.sample_rate = properties.sample_rate();
+    new_song.channels = properties.channels();
+    new_song.bit_depth = properties.bit_depth();
+    new_song.codec = codec_name(tagged_file.file_type()).to_string();
+    (Some(new_song), error)
+}
+
+//Stars out of 5, the scale depends on the tag: ID3v2 keeps ratings in POPM frames as 1-255, the RATING and rate
+//items of the other formats are 0-100. FMPS_RATING, 0.0 to 1.0, is the fallback for every format.
+fn read_rating(tag: &tag::Tag, path: &Path, file_type: FileType) -> Option<u8> {
+    let popularimeter = match tag.get(&tag::ItemKey::Popularimeter).map(|item| item.value()) {
+        Some(tag::ItemValue::Binary(frame)) => PopularimeterFrame::parse(&mut &frame[..], FrameFlags::default()).ok().and_then(|p| popm_stars(p.rating)),
+        Some(tag::ItemValue::Text(value)) => value.trim().parse::<f32>().ok().map(|v| v / 20.0),
+        //lofty leaves POPM frames out of the generic tag, they're only in the ID3v2 tag itself
+        _ if tag.tag_type() == tag::TagType::Id3v2 => id3v2_rating(path, file_type),
+        _ => None,
+    };
+    let stars = match popularimeter {
+        Some(stars) => stars,
+        None => {
+            let fmps = tag.items()
+                .find(|item| matches!(item.key(), tag::ItemKey::Unknown(k) if k.eq_ignore_ascii_case("FMPS_RATING")))
+                .and_then(|item| item.value().text())?;
+            fmps.trim().parse::<f32>().ok()? * 5.0
+        }
+    };
+    Some(stars.round().clamp(0.0, 5.0) as u8)
+}
+
+//Reads the file's ID3v2 tag again on its own, for the first POPM frame with a rating.
+fn id3v2_rating(path: &Path, file_type: FileType) -> Option<f32> {
+    let mut file = fs::File::open(path).ok()?;
+    let options = ParseOptions::new().read_properties(false).read_cover_art(false);
+    let id3v2 = match file_type {
+        FileType::Mpeg => MpegFile::read_from(&mut file, options).ok()?.remove_id3v2(),
+        FileType::Aac => AacFile::read_from(&mut file, options).ok()?.remove_id3v2(),
+        FileType::Aiff => AiffFile::read_from(&mut file, options).ok()?.remove_id3v2(),
+        FileType::Wav => WavFile::read_from(&mut file, options).ok()?.remove_id3v2(),
+        _ => None,
+    }?;
+    id3v2.into_iter().find_map(|frame| match frame {
+        Frame::Popularimeter(popm) => popm_stars(popm.rating),
+        _ => None,
+    })
+}
+
+//POPM's rating byte, 0 is unrated. Taggers write 1, 64, 128, 196 and 255 for one to five stars.
+fn popm_stars(rating: u8) -> Option<f32> {
+    match rating {
+        0 => None,
+        1..=31 => Some(1.0),
+        32..=95 => Some(2.0),
+        96..=159 => Some(3.0),
+        160..=223 => Some(4.0),
+        _ => Some(5.0),
+    }
+}
+
+fn codec_name(file_type: FileType) -> &'static str {
+    match file_type {
+        FileType::Aac => "AAC",
+        FileType::Aiff => "AIFF",
+        FileType::Ape => "APE",
+        FileType::Flac => "FLAC",
+        FileType::Mpeg => "MP3",
+        FileType::Mp4 => "MP4",
+        FileType::Mpc => "MPC",
+        FileType::Opus => "Opus",
+        FileType::Vorbis => "Vorbis",
+        FileType::Speex => "Speex",
+        FileType::Wav => "WAV",
+        FileType::WavPack => "WavPack",
+        _ => "Unknown",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //Fresh folder under the system temp dir with the given files, `.coralignore` contents included
+    fn library(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("coral_scan_{}_{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&root);
+        for (path, contents) in files {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+        root
+    }
+
+    fn relative(files: &[PathBuf], root: &Path) -> Vec<String> {
+        files.iter().map(|f| f.strip_prefix(root).unwrap().to_string_lossy().replace('\\', "/")).collect()
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(wildcard_match("*.jpg", "cover.jpg"));
+        assert!(!wildcard_match("*.jpg", "cover.jpeg"));
+        assert!(wildcard_match("track?.mp3", "track1.mp3"));
+        assert!(!wildcard_match("track?.mp3", "track10.mp3"));
+        assert!(wildcard_match("*live*", "Live at the BBC - live"));
+        assert!(wildcard_match("a*b*c", "aXbYbZc"));
+        assert!(!wildcard_match("a*b*c", "aXbYc d"));
+        assert!(wildcard_match("*", ""));
+    }
+
+    #[test]
+    fn collect_honours_ignore_files_hidden_and_depth() {
+        let root = library("collect", &[
+            (".coralignore", "*.txt\nscans/\n"),
+            ("a.mp3", ""),
+            ("notes.txt", ""),
+            (".hidden.mp3", ""),
+            ("scans/front.mp3", ""),
+            ("Album/b.mp3", ""),
+            ("Album/Disc/c.mp3", ""),
+            ("Album/.coralignore", "c.mp3\n"),
+            ("Deep/One/d.mp3", ""),
+            ("Deep/One/Two/e.mp3", ""),
+        ]);
+        let options = ScanOptions { max_depth: 2, ..Default::default() };
+        let files = relative(&collect_files(&root, &options), &root);
+        assert_eq!(files, ["Album/b.mp3", "Deep/One/d.mp3", "a.mp3"]);
+        let shallow = ScanOptions { max_depth: 1, skip_hidden: false, ..Default::default() };
+        let files = relative(&collect_files(&root, &shallow), &root);
+        assert_eq!(files, [".hidden.mp3", "Album/b.mp3", "a.mp3"]);
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn watched_paths_get_the_rules_of_their_library_root() {
+        let root = library("watched", &[
+            (".coralignore", "Rips/\n"),
+            ("Rips/new.mp3", ""),
+            (".hidden/x.mp3", ""),
+            ("Album/.coralignore", "*.tmp\n"),
+            ("Album/b.mp3", ""),
+            ("Album/b.tmp", ""),
+            ("Deep/One/Two/e.mp3", ""),
+        ]);
+        let dirs = vec![root.clone()];
+        let options = ScanOptions { max_depth: 2, ..Default::default() };
+        let collect = |path: &str| relative(&collect_root(&root.join(path), &dirs, &options), &root);
+        assert!(collect("Rips/new.mp3").is_empty());
+        assert!(collect("Rips").is_empty());
+        assert!(collect(".hidden/x.mp3").is_empty());
+        assert!(collect("Album/b.tmp").is_empty());
+        assert!(collect("Deep/One/Two/e.mp3").is_empty());
+        assert!(collect("Deep/One/Two").is_empty());
+        assert_eq!(collect("Album/b.mp3"), ["Album/b.mp3"]);
+        assert_eq!(collect("Album"), ["Album/b.mp3"]);
+        let _ = fs::remove_dir_all(&root);
+    }
+}
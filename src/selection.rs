@@ -0,0 +1,170 @@
+use std::{collections::HashSet, hash::Hash};
+
+/// Rows picked in a list with ctrl and shift clicks. Rows are kept by id rather than index, so the selection
+/// stays on the same rows when the list is reordered.
+pub struct Selection<T> {
+    items: HashSet<T>,
+    anchor: Option<T>,  //row a shift-click range starts from, the last one clicked without shift
+}
+
+impl<T> Default for Selection<T> {
+    fn default() -> Self {
+        Selection {
+            items: HashSet::new(),
+            anchor: None,
+        }
+    }
+}
+
+impl<T: Copy + Eq + Hash> Selection<T> {
+    /// Applies a click on `item`, with `order` being the rows as they're listed. Ctrl toggles the row, shift
+    /// selects everything between it and the last clicked row, a plain click selects only it.
+    /// Returns true for a plain click, which the list can treat as activating the row.
+    pub fn click(&mut self, item: T, order: &[T], modifiers: egui::Modifiers) -> bool {
+        if modifiers.shift {
+            let anchor = self.anchor.and_then(|a| order.iter().position(|i| *i == a));
+            let clicked = order.iter().position(|i| *i == item);
+            if !modifiers.command {
+                self.items.clear();
+            }
+            match anchor.zip(clicked) {
+                Some((a, c)) => self.items.extend(order[a.min(c)..=a.max(c)].iter().copied()),
+                None => {
+                    self.items.insert(item);
+                    self.anchor = Some(item);
+                }
+            }
+            return false;
+        }
+        self.anchor = Some(item);
+        if modifiers.command {
+            if !self.items.remove(&item) {
+                self.items.insert(item);
+            }
+            return false;
+        }
+        self.items.clear();
+        self.items.insert(item);
+        true
+    }
+
+    pub fn select_all(&mut self, order: &[T]) {
+        self.items = order.iter().copied().collect();
+    }
+
+    pub fn clear(&mut self) {
+        self.items.clear();
+        self.anchor = None;
+    }
+
+    pub fn contains(&self, item: &T) -> bool {
+        self.items.contains(item)
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// Whether an action on `item` should apply to the whole selection rather than just that row.
+    pub fn covers(&self, item: &T) -> bool {
+        self.items.len() > 1 && self.items.contains(item)
+    }
+
+    /// Rows an action on `item` applies to, the whole selection when it's part of it and otherwise just `item`.
+    pub fn targets(&self, item: T) -> HashSet<T> {
+        if self.covers(&item) { self.items.clone() } else { HashSet::from([item]) }
+    }
+
+    /// Selected rows in the order they're listed in. Rows that aren't listed anymore are left out.
+    pub fn ordered(&self, order: &[T]) -> Vec<T> {
+        order.iter().filter(|i| self.items.contains(i)).copied().collect()
+    }
+
+    pub fn items(&self) -> &HashSet<T> {
+        &self.items
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ORDER: [u32; 6] = [10, 20, 30, 40, 50, 60];
+
+    fn modifiers(command: bool, shift: bool) -> egui::Modifiers {
+        egui::Modifiers { command, shift, ..Default::default() }
+    }
+
+    fn selected(selection: &Selection<u32>) -> Vec<u32> {
+        selection.ordered(&ORDER)
+    }
+
+    #[test]
+    fn plain_click_selects_only_the_row() {
+        let mut selection = Selection::default();
+        assert!(selection.click(20, &ORDER, modifiers(false, false)));
+        assert!(selection.click(40, &ORDER, modifiers(false, false)));
+        assert_eq!(selected(&selection), [40]);
+    }
+
+    #[test]
+    fn ctrl_click_toggles() {
+        let mut selection = Selection::default();
+        selection.click(20, &ORDER, modifiers(false, false));
+        assert!(!selection.click(40, &ORDER, modifiers(true, false)));
+        assert_eq!(selected(&selection), [20, 40]);
+        selection.click(20, &ORDER, modifiers(true, false));
+        assert_eq!(selected(&selection), [40]);
+    }
+
+    #[test]
+    fn shift_click_selects_a_range_from_the_anchor() {
+        let mut selection = Selection::default();
+        selection.click(50, &ORDER, modifiers(false, false));
+        selection.click(20, &ORDER, modifiers(false, true));
+        assert_eq!(selected(&selection), [20, 30, 40, 50]);
+        //the anchor stays, so the range shrinks back
+        selection.click(40, &ORDER, modifiers(false, true));
+        assert_eq!(selected(&selection), [40, 50]);
+        //with ctrl as well the range is added to what's there
+        selection.click(10, &ORDER, modifiers(true, false));
+        selection.click(30, &ORDER, modifiers(true, true));
+        assert_eq!(selected(&selection), [10, 20, 30, 40, 50]);
+    }
+
+    #[test]
+    fn shift_click_without_anchor_selects_the_row() {
+        let mut selection = Selection::default();
+        selection.click(30, &ORDER, modifiers(false, true));
+        assert_eq!(selected(&selection), [30]);
+        selection.click(50, &ORDER, modifiers(false, true));
+        assert_eq!(selected(&selection), [30, 40, 50]);
+    }
+
+    #[test]
+    fn targets_and_covers() {
+        let mut selection = Selection::default();
+        selection.click(20, &ORDER, modifiers(false, false));
+        assert!(!selection.covers(&20));
+        assert_eq!(selection.targets(40), HashSet::from([40]));
+        selection.click(30, &ORDER, modifiers(true, false));
+        assert!(selection.covers(&20));
+        assert_eq!(selection.targets(30), HashSet::from([20, 30]));
+        assert_eq!(selection.targets(60), HashSet::from([60]));
+    }
+
+    #[test]
+    fn select_all_clear_and_missing_rows() {
+        let mut selection = Selection::default();
+        selection.select_all(&ORDER);
+        assert_eq!(selection.len(), ORDER.len());
+        assert_eq!(selection.ordered(&[30, 99, 10]), [30, 10]);
+        selection.clear();
+        assert!(selection.is_empty());
+        assert!(!selection.contains(&10));
+    }
+}
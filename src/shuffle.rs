@@ -0,0 +1,214 @@
+use std::{hash::Hash, path::PathBuf};
+use egui::ahash::HashMap;
+use rand::{seq::SliceRandom, Rng};
+use crate::library::{AlbumKey, LibraryInfo, SongId, SongStore};
+
+/// How Shuffle Play and Shuffle Queue put the songs in order.
+#[derive(Clone, Copy, PartialEq, Default)]
+pub enum ShuffleMode {
+    #[default]
+    Random,
+    SpreadArtists,  //songs by the same artist are spaced out instead of landing in clumps
+    SpreadAlbums,
+    Albums,         //albums in random order, each one played through in track order
+    ByRating,       //higher rated songs tend to come up sooner
+    ByPlayCount,    //songs played often tend to come up sooner
+}
+
+impl ShuffleMode {
+    pub const ALL: [ShuffleMode; 6] = [
+        ShuffleMode::Random,
+        ShuffleMode::SpreadArtists,
+        ShuffleMode::SpreadAlbums,
+        ShuffleMode::Albums,
+        ShuffleMode::ByRating,
+        ShuffleMode::ByPlayCount,
+    ];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            ShuffleMode::Random => "Random",
+            ShuffleMode::SpreadArtists => "Spread artists",
+            ShuffleMode::SpreadAlbums => "Spread albums",
+            ShuffleMode::Albums => "Album shuffle",
+            ShuffleMode::ByRating => "Favour rating",
+            ShuffleMode::ByPlayCount => "Favour play count",
+        }
+    }
+
+    pub fn from_label(label: &str) -> Option<ShuffleMode> {
+        ShuffleMode::ALL.into_iter().find(|m| m.label() == label)
+    }
+}
+
+/// What the shuffle modes look at besides the song ids.
+pub struct ShuffleContext<'a> {
+    pub songs: &'a SongStore,
+    pub library: &'a LibraryInfo,
+    pub play_counts: &'a HashMap<PathBuf, u32>,
+}
+
+/// A new order for `songs`, as indices into it.
+pub fn shuffled_order(songs: &[SongId], mode: ShuffleMode, context: &ShuffleContext) -> Vec<usize> {
+    let mut rng = rand::rng();
+    match mode {
+        ShuffleMode::Random => {
+            let mut order: Vec<usize> = (0..songs.len()).collect();
+            order.shuffle(&mut rng);
+            order
+        }
+        ShuffleMode::SpreadArtists => spread(songs, |id| context.songs.get(id).map(|s| s.artist.to_lowercase()), &mut rng),
+        ShuffleMode::SpreadAlbums => spread(songs, |id| context.library.song_album.get(&id), &mut rng),
+        ShuffleMode::Albums => album_order(songs, context.library, &mut rng),
+        ShuffleMode::ByRating => weighted(songs, |id| {
+            //unrated songs count as two and a half stars, zero stars still get a small chance
+            context.songs.get(id).and_then(|s| s.rating).map(|r| r as f64 + 0.5).unwrap_or(3.0)
+        }, &mut rng),
+        ShuffleMode::ByPlayCount => weighted(songs, |id| {
+            //square root so a handful of heavily played songs don't crowd out everything else
+            let plays = context.songs.get(id).and_then(|s| context.play_counts.get(&s.path)).copied().unwrap_or(0);
+            1.0 + (plays as f64).sqrt()
+        }, &mut rng),
+    }
+}
+
+//Balanced shuffle: every group is shuffled on its own and its songs get evenly spaced positions from a random
+//offset, so two songs of one group only end up next to each other when there's little else to put between them.
+fn spread<K: Eq + Hash>(songs: &[SongId], key: impl Fn(SongId) -> K, rng: &mut impl Rng) -> Vec<usize> {
+    let mut groups: HashMap<K, Vec<usize>> = HashMap::default();
+    for (i, id) in songs.iter().enumerate() {
+        groups.entry(key(*id)).or_default().push(i);
+    }
+    let mut placed: Vec<(f64, usize)> = Vec::with_capacity(songs.len());
+    for (_, mut group) in groups {
+        group.shuffle(rng);
+        let spacing = 1.0 / group.len() as f64;
+        let offset = rng.random::<f64>() * spacing;
+        for (n, i) in group.into_iter().enumerate() {
+            //a bit of jitter, or groups of the same size would always take turns in the same order
+            let jitter = (rng.random::<f64>() - 0.5) * spacing * 0.2;
+            placed.push((offset + n as f64 * spacing + jitter, i));
+        }
+    }
+    placed.sort_by(|a, b| a.0.total_cmp(&b.0));
+    placed.into_iter().map(|(_, i)| i).collect()
+}
+
+//Albums in random order with their songs in track order. Songs that aren't part of an album go together.
+fn album_order(songs: &[SongId], library: &LibraryInfo, rng: &mut impl Rng) -> Vec<usize> {
+    let mut groups: Vec<(Option<&AlbumKey>, Vec<usize>)> = Vec::new();
+    let mut group_of: HashMap<Option<&AlbumKey>, usize> = HashMap::default();
+    for (i, id) in songs.iter().enumerate() {
+        let album = library.song_album.get(id);
+        let group = *group_of.entry(album).or_insert_with(|| {
+            groups.push((album, Vec::new()));
+            groups.len() - 1
+        });
+        groups[group].1.push(i);
+    }
+    for (album, group) in &mut groups {
+        if let Some(info) = album.and_then(|album| library.albums.get(album)) {
+            group.sort_by_key(|i| info.songs.iter().position(|id| *id == songs[*i]));
+        }
+    }
+    groups.shuffle(rng);
+    groups.into_iter().flat_map(|(_, group)| group).collect()
+}
+
+//Weighted random order (Efraimidis-Spirakis): every song draws u^(1/weight) and the highest draws go first,
+//so heavier songs tend to come early without lighter ones ever being ruled out.
+fn weighted(songs: &[SongId], weight: impl Fn(SongId) -> f64, rng: &mut impl Rng) -> Vec<usize> {
+    let mut keyed: Vec<(f64, usize)> = songs.iter().enumerate()
+        .map(|(i, id)| (rng.random::<f64>().powf(1.0 / weight(*id).max(0.01)), i))
+        .collect();
+    keyed.sort_by(|a, b| b.0.total_cmp(&a.0));
+    keyed.into_iter().map(|(_, i)| i).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::library::SongInfo;
+
+    //Songs by two artists, each with an album of `per_artist` tracks
+    fn library(per_artist: usize) -> (SongStore, Vec<SongId>) {
+        let mut store = SongStore::default();
+        let mut ids = Vec::new();
+        for artist in ["A", "B"] {
+            for track in 1..=per_artist {
+                let song = SongInfo {
+                    path: PathBuf::from(format!("{artist}/{track}.mp3")),
+                    artist: artist.to_string(),
+                    album: format!("{artist} album"),
+                    album_artist: Some(artist.to_string()),
+                    track_number: Some(track),
+                    ..Default::default()
+                };
+                ids.push(store.insert(song).0);
+            }
+        }
+        (store, ids)
+    }
+
+    fn is_permutation(order: &[usize], len: usize) -> bool {
+        let mut sorted = order.to_vec();
+        sorted.sort();
+        sorted == (0..len).collect::<Vec<_>>()
+    }
+
+    #[test]
+    fn every_mode_keeps_every_song_once() {
+        let (store, ids) = library(7);
+        let library = LibraryInfo::rebuild(&store);
+        let counts = HashMap::default();
+        let context = ShuffleContext { songs: &store, library: &library, play_counts: &counts };
+        for mode in ShuffleMode::ALL {
+            assert!(is_permutation(&shuffled_order(&ids, mode, &context), ids.len()), "{}", mode.label());
+        }
+        assert!(shuffled_order(&[], ShuffleMode::SpreadArtists, &context).is_empty());
+    }
+
+    #[test]
+    fn spread_keeps_artists_apart() {
+        let (store, ids) = library(10);
+        let library = LibraryInfo::rebuild(&store);
+        let counts = HashMap::default();
+        let context = ShuffleContext { songs: &store, library: &library, play_counts: &counts };
+        let artist = |i: usize| &store.get(ids[i]).unwrap().artist;
+        let trials = 200;
+        let mut neighbours = 0;
+        for _ in 0..trials {
+            let order = shuffled_order(&ids, ShuffleMode::SpreadArtists, &context);
+            neighbours += order.windows(2).filter(|w| artist(w[0]) == artist(w[1])).count();
+        }
+        //a plain shuffle puts about 9 of the 19 pairs next to the same artist
+        assert!((neighbours as f64 / trials as f64) < 3.0);
+    }
+
+    #[test]
+    fn album_shuffle_plays_albums_through_in_order() {
+        let (store, ids) = library(5);
+        let library = LibraryInfo::rebuild(&store);
+        let counts = HashMap::default();
+        let context = ShuffleContext { songs: &store, library: &library, play_counts: &counts };
+        for _ in 0..20 {
+            let order = shuffled_order(&ids, ShuffleMode::Albums, &context);
+            assert!(order == (0..10).collect::<Vec<_>>() || order == (5..10).chain(0..5).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn heavier_songs_tend_to_come_first() {
+        let (_, ids) = library(1);
+        let mut rng = rand::rng();
+        let mut wins = 0;
+        for _ in 0..1000 {
+            let order = weighted(&ids, |id| if id == ids[0] { 10.0 } else { 1.0 }, &mut rng);
+            if order[0] == 0 {
+                wins += 1;
+            }
+        }
+        //10 / 11 of the time on average
+        assert!(wins > 820, "{wins}");
+    }
+}
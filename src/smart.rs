@@ -0,0 +1,502 @@
+use std::{cmp::Ordering, path::PathBuf, time::{Duration, SystemTime}};
+use egui::ahash::HashMap;
+use serde::{Deserialize, Serialize};
+use crate::library::{SongId, SongInfo, SongStore};
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Song property a rule looks at.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub enum Field {
+    Title,
+    Artist,
+    Album,
+    AlbumArtist,
+    Genre,
+    Composer,
+    Year,
+    Rating,
+    PlayCount,
+    Duration,   //in minutes
+    DateAdded,  //compared in days before now
+}
+
+/// How a rule's value is read and which operators make sense for it.
+#[derive(Clone, Copy, PartialEq)]
+pub enum FieldKind {
+    Text,
+    Number,
+    Date,
+}
+
+impl Field {
+    pub const ALL: [Field; 11] = [
+        Field::Title,
+        Field::Artist,
+        Field::Album,
+        Field::AlbumArtist,
+        Field::Genre,
+        Field::Composer,
+        Field::Year,
+        Field::Rating,
+        Field::PlayCount,
+        Field::Duration,
+        Field::DateAdded,
+    ];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Field::Title => "Title",
+            Field::Artist => "Artist",
+            Field::Album => "Album",
+            Field::AlbumArtist => "Album artist",
+            Field::Genre => "Genre",
+            Field::Composer => "Composer",
+            Field::Year => "Year",
+            Field::Rating => "Rating",
+            Field::PlayCount => "Play count",
+            Field::Duration => "Duration",
+            Field::DateAdded => "Date added",
+        }
+    }
+
+    pub fn kind(&self) -> FieldKind {
+        match self {
+            Field::Title | Field::Artist | Field::Album | Field::AlbumArtist | Field::Genre | Field::Composer => FieldKind::Text,
+            Field::Year | Field::Rating | Field::PlayCount | Field::Duration => FieldKind::Number,
+            Field::DateAdded => FieldKind::Date,
+        }
+    }
+
+    /// What the value is counted in, shown next to the value box.
+    pub fn unit(&self) -> &'static str {
+        match self {
+            Field::Rating => "stars",
+            Field::PlayCount => "plays",
+            Field::Duration => "minutes",
+            Field::DateAdded => "days",
+            _ => "",
+        }
+    }
+
+    fn text<'a>(&self, song: &'a SongInfo) -> Option<&'a str> {
+        match self {
+            Field::Title => Some(&song.track),
+            Field::Artist => Some(&song.artist),
+            Field::Album => Some(&song.album),
+            Field::AlbumArtist => song.album_artist.as_deref(),
+            Field::Genre => song.genre.as_deref(),
+            Field::Composer => song.composer.as_deref(),
+            _ => None,
+        }
+    }
+
+    fn number(&self, song: &SongInfo, context: &RuleContext) -> Option<f64> {
+        match self {
+            Field::Year => song.year.map(|y| y as f64),
+            Field::Rating => song.rating.map(|r| r as f64),
+            Field::PlayCount => Some(context.play_count(song) as f64),
+            Field::Duration => Some(song.duration.as_secs_f64() / 60.0),
+            _ => None,
+        }
+    }
+}
+
+/// Comparison a rule makes, which ones apply depends on the field's kind.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub enum Operator {
+    Is,
+    IsNot,
+    Contains,
+    NotContains,
+    Less,
+    Greater,
+    AtMost,
+    AtLeast,
+    InLast,
+    NotInLast,
+}
+
+impl Operator {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Operator::Is => "is",
+            Operator::IsNot => "is not",
+            Operator::Contains => "contains",
+            Operator::NotContains => "doesn't contain",
+            Operator::Less => "<",
+            Operator::Greater => ">",
+            Operator::AtMost => "≤",
+            Operator::AtLeast => "≥",
+            Operator::InLast => "in the last",
+            Operator::NotInLast => "not in the last",
+        }
+    }
+
+    pub fn for_kind(kind: FieldKind) -> &'static [Operator] {
+        match kind {
+            FieldKind::Text => &[Operator::Is, Operator::IsNot, Operator::Contains, Operator::NotContains],
+            FieldKind::Number => &[Operator::Is, Operator::IsNot, Operator::Less, Operator::Greater, Operator::AtMost, Operator::AtLeast],
+            FieldKind::Date => &[Operator::InLast, Operator::NotInLast],
+        }
+    }
+}
+
+/// One condition, e.g. genre is Jazz. The value is kept as typed and parsed when the rule is checked.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Rule {
+    pub field: Field,
+    pub operator: Operator,
+    pub value: String,
+}
+
+impl Default for Rule {
+    fn default() -> Self {
+        Rule {
+            field: Field::Genre,
+            operator: Operator::Is,
+            value: String::new(),
+        }
+    }
+}
+
+impl Rule {
+    /// Switches the field, picking an operator that goes with it when the current one doesn't.
+    pub fn set_field(&mut self, field: Field) {
+        self.field = field;
+        let operators = Operator::for_kind(field.kind());
+        if !operators.contains(&self.operator) {
+            self.operator = operators[0];
+        }
+    }
+
+    //Songs missing the field never match, not even "is not" or "doesn't contain". A value that doesn't
+    //parse as a number doesn't match anything either.
+    fn matches(&self, song: &SongInfo, context: &RuleContext) -> bool {
+        match self.field.kind() {
+            FieldKind::Text => {
+                let Some(text) = self.field.text(song) else {
+                    return false;
+                };
+                let text = text.to_lowercase();
+                let value = self.value.trim().to_lowercase();
+                match self.operator {
+                    Operator::Is => text == value,
+                    Operator::IsNot => text != value,
+                    Operator::Contains => text.contains(&value),
+                    Operator::NotContains => !text.contains(&value),
+                    _ => false,
+                }
+            }
+            FieldKind::Number => {
+                let (Some(number), Ok(value)) = (self.field.number(song, context), self.value.trim().parse::<f64>()) else {
+                    return false;
+                };
+                match self.operator {
+                    Operator::Is => number == value,
+                    Operator::IsNot => number != value,
+                    Operator::Less => number < value,
+                    Operator::Greater => number > value,
+                    Operator::AtMost => number <= value,
+                    Operator::AtLeast => number >= value,
+                    _ => false,
+                }
+            }
+            FieldKind::Date => {
+                let Ok(days) = self.value.trim().parse::<f64>() else {
+                    return false;
+                };
+                let age = context.now.duration_since(song.added).unwrap_or_default();
+                let recent = age.as_secs_f64() <= days * SECS_PER_DAY as f64;
+                match self.operator {
+                    Operator::InLast => recent,
+                    Operator::NotInLast => !recent,
+                    _ => false,
+                }
+            }
+        }
+    }
+}
+
+/// Order the songs of a smart playlist are listed and cut off in.
+#[derive(Clone, Copy, PartialEq, Debug, Default, Serialize, Deserialize)]
+pub enum SmartSort {
+    #[default]
+    Artist,
+    Album,
+    Title,
+    Year,
+    Rating,
+    PlayCount,
+    Duration,
+    DateAdded,
+}
+
+impl SmartSort {
+    pub const ALL: [SmartSort; 8] = [
+        SmartSort::Artist,
+        SmartSort::Album,
+        SmartSort::Title,
+        SmartSort::Year,
+        SmartSort::Rating,
+        SmartSort::PlayCount,
+        SmartSort::Duration,
+        SmartSort::DateAdded,
+    ];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            SmartSort::Artist => "Artist",
+            SmartSort::Album => "Album",
+            SmartSort::Title => "Title",
+            SmartSort::Year => "Year",
+            SmartSort::Rating => "Rating",
+            SmartSort::PlayCount => "Play count",
+            SmartSort::Duration => "Duration",
+            SmartSort::DateAdded => "Date added",
+        }
+    }
+
+    fn compare(&self, a: &SongInfo, b: &SongInfo, context: &RuleContext) -> Ordering {
+        match self {
+            SmartSort::Artist => compare_text(&a.artist, &b.artist),
+            SmartSort::Album => compare_text(&a.album, &b.album),
+            SmartSort::Title => compare_text(&a.track, &b.track),
+            SmartSort::Year => a.year.cmp(&b.year),
+            SmartSort::Rating => a.rating.cmp(&b.rating),
+            SmartSort::PlayCount => context.play_count(a).cmp(&context.play_count(b)),
+            SmartSort::Duration => a.duration.cmp(&b.duration),
+            SmartSort::DateAdded => a.added.cmp(&b.added),
+        }
+    }
+}
+
+fn compare_text(a: &str, b: &str) -> Ordering {
+    a.to_lowercase().cmp(&b.to_lowercase())
+}
+
+//albums stay in track order whatever they're sorted by
+fn album_order(a: &SongInfo, b: &SongInfo) -> Ordering {
+    compare_text(&a.album, &b.album).then(a.disc_number.cmp(&b.disc_number)).then(a.track_number.cmp(&b.track_number))
+}
+
+/// A playlist that's whatever songs of the library match its rules right now.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct SmartPlaylist {
+    pub id: u64,
+    pub name: String,
+    pub rules: Vec<Rule>,
+    pub match_all: bool,        //every rule has to match, otherwise any one of them is enough
+    pub sort: SmartSort,
+    pub descending: bool,
+    pub limit: Option<usize>,   //songs kept after sorting
+}
+
+impl Default for SmartPlaylist {
+    fn default() -> Self {
+        SmartPlaylist {
+            id: 0,
+            name: "New Smart Playlist".to_string(),
+            rules: vec![Rule::default()],
+            match_all: true,
+            sort: SmartSort::default(),
+            descending: false,
+            limit: None,
+        }
+    }
+}
+
+impl SmartPlaylist {
+    /// The library songs that match, sorted and limited. Without rules nothing matches.
+    pub fn evaluate(&self, songs: &SongStore, context: &RuleContext) -> Vec<SongId> {
+        if self.rules.is_empty() {
+            return Vec::new();
+        }
+        let mut matched: Vec<(SongId, &SongInfo)> = songs.iter()
+            .filter(|(_, song)| {
+                if self.match_all {
+                    self.rules.iter().all(|r| r.matches(song, context))
+                } else {
+                    self.rules.iter().any(|r| r.matches(song, context))
+                }
+            })
+            .collect();
+        matched.sort_by(|a, b| {
+            let order = self.sort.compare(a.1, b.1, context);
+            let order = if self.descending { order.reverse() } else { order };
+            //ties always come out the same way, or a limit would keep a different few of them every time
+            order.then_with(|| album_order(a.1, b.1)).then_with(|| a.1.path.cmp(&b.1.path))
+        });
+        if let Some(limit) = self.limit {
+            matched.truncate(limit);
+        }
+        matched.into_iter().map(|(id, _)| id).collect()
+    }
+}
+
+/// What rules look at besides the songs themselves.
+pub struct RuleContext<'a> {
+    pub play_counts: &'a HashMap<PathBuf, u32>,
+    pub now: SystemTime,
+}
+
+impl RuleContext<'_> {
+    fn play_count(&self, song: &SongInfo) -> u32 {
+        self.play_counts.get(&song.path).copied().unwrap_or(0)
+    }
+}
+
+/// Total length of a list of songs for the sidebar, e.g. "42 songs, 3 h 5 min".
+pub fn describe(songs: &[SongId], store: &SongStore) -> String {
+    let total: Duration = songs.iter().filter_map(|id| store.get(*id)).map(|s| s.duration).sum();
+    let minutes = total.as_secs() / 60;
+    let length = if minutes >= 60 { format!("{} h {} min", minutes / 60, minutes % 60) } else { format!("{} min", minutes) };
+    format!("{} songs, {}", songs.len(), length)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn song(path: &str, rating: Option<u8>) -> SongInfo {
+        SongInfo {
+            path: PathBuf::from(path),
+            artist: "Artist".to_string(),
+            track: path.to_string(),
+            album: "Album".to_string(),
+            rating,
+            ..Default::default()
+        }
+    }
+
+    fn rule(field: Field, operator: Operator, value: &str) -> Rule {
+        Rule { field, operator, value: value.to_string() }
+    }
+
+    fn paths(ids: &[SongId], store: &SongStore) -> Vec<String> {
+        ids.iter().map(|id| store.get(*id).unwrap().path.to_string_lossy().to_string()).collect()
+    }
+
+    #[test]
+    fn text_rules_ignore_case_and_missing_fields() {
+        let counts = HashMap::default();
+        let context = RuleContext { play_counts: &counts, now: SystemTime::now() };
+        let mut jazz = song("a", None);
+        jazz.genre = Some("Jazz".to_string());
+        assert!(rule(Field::Genre, Operator::Is, "jazz").matches(&jazz, &context));
+        assert!(rule(Field::Genre, Operator::Contains, "AZ").matches(&jazz, &context));
+        assert!(!rule(Field::Genre, Operator::IsNot, "Jazz").matches(&jazz, &context));
+        let untagged = song("b", None);
+        assert!(!rule(Field::Genre, Operator::IsNot, "Jazz").matches(&untagged, &context));
+        assert!(!rule(Field::Genre, Operator::NotContains, "Rock").matches(&untagged, &context));
+    }
+
+    #[test]
+    fn number_rules() {
+        let mut counts = HashMap::default();
+        counts.insert(PathBuf::from("a"), 11);
+        let context = RuleContext { play_counts: &counts, now: SystemTime::now() };
+        let mut old = song("a", Some(4));
+        old.year = Some(1965);
+        old.duration = Duration::from_secs(9 * 60);
+        assert!(rule(Field::Year, Operator::Less, "1970").matches(&old, &context));
+        assert!(rule(Field::PlayCount, Operator::Greater, "10").matches(&old, &context));
+        assert!(rule(Field::Duration, Operator::Greater, "8").matches(&old, &context));
+        assert!(rule(Field::Rating, Operator::AtLeast, "4").matches(&old, &context));
+        assert!(!rule(Field::Rating, Operator::AtLeast, "four").matches(&old, &context));
+        assert!(rule(Field::PlayCount, Operator::Is, "0").matches(&song("b", None), &context));
+        assert!(!rule(Field::Year, Operator::IsNot, "1965").matches(&song("b", None), &context));
+    }
+
+    #[test]
+    fn date_added_rules() {
+        let counts = HashMap::default();
+        let now = SystemTime::now();
+        let context = RuleContext { play_counts: &counts, now };
+        let mut recent = song("a", None);
+        recent.added = now - Duration::from_secs(5 * SECS_PER_DAY);
+        let mut old = song("b", None);
+        old.added = now - Duration::from_secs(60 * SECS_PER_DAY);
+        let in_last_month = rule(Field::DateAdded, Operator::InLast, "30");
+        assert!(in_last_month.matches(&recent, &context));
+        assert!(!in_last_month.matches(&old, &context));
+        assert!(rule(Field::DateAdded, Operator::NotInLast, "30").matches(&old, &context));
+    }
+
+    #[test]
+    fn set_field_picks_a_fitting_operator() {
+        let mut rule = rule(Field::Genre, Operator::Contains, "");
+        rule.set_field(Field::Year);
+        assert_eq!(rule.operator, Operator::Is);
+        rule.operator = Operator::Greater;
+        rule.set_field(Field::PlayCount);
+        assert_eq!(rule.operator, Operator::Greater);
+        rule.set_field(Field::DateAdded);
+        assert_eq!(rule.operator, Operator::InLast);
+    }
+
+    #[test]
+    fn match_all_and_any() {
+        let mut store = SongStore::default();
+        let mut jazz = song("jazz", Some(5));
+        jazz.genre = Some("Jazz".to_string());
+        store.insert(jazz);
+        store.insert(song("rated", Some(5)));
+        store.insert(song("plain", None));
+        let counts = HashMap::default();
+        let context = RuleContext { play_counts: &counts, now: SystemTime::now() };
+        let mut playlist = SmartPlaylist {
+            rules: vec![rule(Field::Genre, Operator::Is, "Jazz"), rule(Field::Rating, Operator::Is, "5")],
+            sort: SmartSort::Title,
+            ..Default::default()
+        };
+        assert_eq!(paths(&playlist.evaluate(&store, &context), &store), ["jazz"]);
+        playlist.match_all = false;
+        assert_eq!(paths(&playlist.evaluate(&store, &context), &store), ["jazz", "rated"]);
+        playlist.rules.clear();
+        assert!(playlist.evaluate(&store, &context).is_empty());
+    }
+
+    #[test]
+    fn ties_at_the_limit_are_cut_the_same_way_every_time() {
+        let counts = HashMap::default();
+        let context = RuleContext { play_counts: &counts, now: SystemTime::now() };
+        let playlist = SmartPlaylist {
+            rules: vec![rule(Field::Rating, Operator::AtLeast, "4")],
+            sort: SmartSort::Rating,
+            descending: true,
+            limit: Some(3),
+            ..Default::default()
+        };
+        let expected = ["top", "a", "b"];
+        //different insertion orders give the store different iteration orders
+        for order in [["a", "b", "c", "d", "top"], ["d", "c", "top", "b", "a"], ["top", "d", "a", "c", "b"]] {
+            let mut store = SongStore::default();
+            for path in order {
+                store.insert(song(path, Some(if path == "top" { 5 } else { 4 })));
+            }
+            store.insert(song("low", Some(2)));
+            assert_eq!(paths(&playlist.evaluate(&store, &context), &store), expected);
+        }
+    }
+
+    #[test]
+    fn albums_keep_track_order_when_descending() {
+        let counts = HashMap::default();
+        let context = RuleContext { play_counts: &counts, now: SystemTime::now() };
+        let mut store = SongStore::default();
+        for (path, album, track) in [("x2", "X", 2), ("y1", "Y", 1), ("x1", "X", 1)] {
+            let mut s = song(path, Some(3));
+            s.album = album.to_string();
+            s.track_number = Some(track);
+            store.insert(s);
+        }
+        let playlist = SmartPlaylist {
+            rules: vec![rule(Field::Rating, Operator::Is, "3")],
+            sort: SmartSort::Album,
+            descending: true,
+            ..Default::default()
+        };
+        assert_eq!(paths(&playlist.evaluate(&store, &context), &store), ["y1", "x1", "x2"]);
+    }
+}
@@ -0,0 +1,76 @@
+use std::{collections::HashSet, path::{Path, PathBuf}, sync::mpsc::{self, Receiver}, time::{Duration, Instant}};
+use notify::{EventKind, RecursiveMode, Watcher};
+
+/// How long the library folders have to be quiet before changes are handed out. Rips and copies
+/// write a file in many small steps, this waits for them to settle instead of rescanning every write.
+const DEBOUNCE: Duration = Duration::from_millis(1500);
+
+/// Watches the library directories and batches up the paths that changed below them.
+pub struct LibraryWatcher {
+    watcher: notify::RecommendedWatcher,
+    receiver: Receiver<notify::Event>,
+    pending: HashSet<PathBuf>,
+    last_event: Instant,
+    ctx: egui::Context,
+}
+
+impl LibraryWatcher {
+    pub fn new(ctx: egui::Context) -> Option<LibraryWatcher> {
+        let (sender, receiver) = mpsc::channel();
+        let repaint_ctx = ctx.clone();
+        let watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+            match res {
+                Ok(event) => {
+                    let _ = sender.send(event);
+                    //the ui only polls while repainting, make sure it looks again once the debounce is over
+                    repaint_ctx.request_repaint_after(DEBOUNCE);
+                }
+                Err(e) => println!("Watch error: {e}"),
+            }
+        });
+        match watcher {
+            Ok(watcher) => Some(LibraryWatcher {
+                watcher,
+                receiver,
+                pending: HashSet::new(),
+                last_event: Instant::now(),
+                ctx,
+            }),
+            Err(e) => {
+                println!("Failed to start directory watcher: {e}");
+                None
+            }
+        }
+    }
+
+    pub fn watch(&mut self, dir: &Path) {
+        if let Err(e) = self.watcher.watch(dir, RecursiveMode::Recursive) {
+            println!("Failed to watch {:?}: {e}", dir);
+        }
+    }
+
+    pub fn unwatch(&mut self, dir: &Path) {
+        let _ = self.watcher.unwatch(dir);
+    }
+
+    /// Returns every path that was created, modified, renamed or removed, but only once no new
+    /// events arrived for the debounce period. Returns nothing while changes are still coming in.
+    pub fn poll(&mut self) -> Vec<PathBuf> {
+        while let Ok(event) = self.receiver.try_recv() {
+            if matches!(event.kind, EventKind::Access(_)) {
+                continue;
+            }
+            self.pending.extend(event.paths);
+            self.last_event = Instant::now();
+        }
+        if self.pending.is_empty() {
+            return Vec::new();
+        }
+        let quiet_for = self.last_event.elapsed();
+        if quiet_for < DEBOUNCE {
+            self.ctx.request_repaint_after(DEBOUNCE - quiet_for);
+            return Vec::new();
+        }
+        self.pending.drain().collect()
+    }
+}